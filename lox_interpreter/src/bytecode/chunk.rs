@@ -0,0 +1,260 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::core::location::Span;
+use crate::value::format_number;
+
+/// The instructions of the virtual machine. Operands follow the opcode in the code: constant
+/// indices and jump offsets take two bytes, big endian, while local slots, upvalue indices and
+/// argument counts take one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    SetProperty,
+    GetSuper,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    CloseUpvalue,
+    Return,
+    Class,
+    Inherit,
+    Method,
+}
+
+// In the same order as the enum, so an opcode's byte is its index here.
+const OPCODES: [OpCode; 37] = [
+    OpCode::Constant,
+    OpCode::Nil,
+    OpCode::True,
+    OpCode::False,
+    OpCode::Pop,
+    OpCode::GetLocal,
+    OpCode::SetLocal,
+    OpCode::GetGlobal,
+    OpCode::DefineGlobal,
+    OpCode::SetGlobal,
+    OpCode::GetUpvalue,
+    OpCode::SetUpvalue,
+    OpCode::GetProperty,
+    OpCode::SetProperty,
+    OpCode::GetSuper,
+    OpCode::Equal,
+    OpCode::Greater,
+    OpCode::GreaterEqual,
+    OpCode::Less,
+    OpCode::LessEqual,
+    OpCode::Add,
+    OpCode::Subtract,
+    OpCode::Multiply,
+    OpCode::Divide,
+    OpCode::Not,
+    OpCode::Negate,
+    OpCode::Print,
+    OpCode::Jump,
+    OpCode::JumpIfFalse,
+    OpCode::Loop,
+    OpCode::Call,
+    OpCode::Closure,
+    OpCode::CloseUpvalue,
+    OpCode::Return,
+    OpCode::Class,
+    OpCode::Inherit,
+    OpCode::Method,
+];
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        OPCODES.get(byte as usize).copied().ok_or(byte)
+    }
+}
+
+/// A value known when compiling, which the VM turns into a runtime value when it loads it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constant::Number(number) => f.write_str(&format_number(*number)),
+            Constant::String(string) => f.write_str(string),
+            Constant::Function(function) => write!(f, "{}", function),
+        }
+    }
+}
+
+/// Where the instructions compiled from a span of the source start. A chunk keeps one of these
+/// per run of instructions from the same span rather than a span for every byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanStart {
+    pub offset: usize,
+    pub span: Span,
+}
+
+/// A compiled sequence of instructions with the constants they refer to and the spans of source
+/// they came from.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Constant>,
+    pub spans: Vec<SpanStart>,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Chunk::default()
+    }
+
+    pub fn write(&mut self, byte: u8, span: Span) {
+        if self.spans.last().map(|start| start.span) != Some(span) {
+            self.spans.push(SpanStart {
+                offset: self.code.len(),
+                span,
+            });
+        }
+        self.code.push(byte);
+    }
+
+    pub fn write_op(&mut self, op: OpCode, span: Span) {
+        self.write(op as u8, span);
+    }
+
+    /// Adds the constant to the pool, reusing an equal number or string that is already there,
+    /// and returns its index.
+    pub fn add_constant(&mut self, constant: Constant) -> usize {
+        let existing = match &constant {
+            Constant::Function(_) => None,
+            _ => self.constants.iter().position(|known| known == &constant),
+        };
+        existing.unwrap_or_else(|| {
+            self.constants.push(constant);
+            self.constants.len() - 1
+        })
+    }
+
+    /// The span of source the instruction at the offset was compiled from.
+    pub fn span(&self, offset: usize) -> Option<Span> {
+        let next = self.spans.partition_point(|start| start.offset <= offset);
+        match next {
+            0 => None,
+            _ => Some(self.spans[next - 1].span),
+        }
+    }
+
+    /// The source line of the instruction at the offset.
+    pub fn line(&self, offset: usize) -> usize {
+        self.span(offset).map_or(0, |span| span.line)
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+}
+
+/// A compiled function. The top level code of a script is compiled to a function too, one with
+/// an empty name and no parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    /// The file the function was compiled from, for naming it in runtime errors.
+    pub filename: String,
+}
+
+impl Function {
+    pub fn new(name: &str, filename: &str) -> Self {
+        Function {
+            name: name.to_string(),
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            filename: filename.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name.is_empty() {
+            true => write!(f, "<script>"),
+            false => write!(f, "<fn {}>", self.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcodes_round_trip_through_bytes() {
+        for op in OPCODES {
+            assert_eq!(OpCode::try_from(op as u8), Ok(op));
+        }
+        assert_eq!(OpCode::try_from(OPCODES.len() as u8), Err(OPCODES.len() as u8));
+    }
+
+    #[test]
+    fn test_spans_are_run_length_encoded() {
+        let (print, condition, end) = (
+            Span::new(0, 5, 1, 1),
+            Span::new(12, 16, 3, 5),
+            Span::new(20, 21, 4, 1),
+        );
+        let mut chunk = Chunk::new();
+        chunk.write_op(OpCode::Nil, print);
+        chunk.write_op(OpCode::Print, print);
+        chunk.write_op(OpCode::True, condition);
+        chunk.write_op(OpCode::Pop, condition);
+        chunk.write_op(OpCode::Return, end);
+        assert_eq!(chunk.spans.len(), 3);
+        let lines: Vec<usize> = (0..chunk.code.len()).map(|offset| chunk.line(offset)).collect();
+        assert_eq!(lines, vec![1, 1, 3, 3, 4]);
+        assert_eq!(chunk.span(3), Some(condition));
+        assert_eq!(Chunk::new().span(0), None);
+    }
+
+    #[test]
+    fn test_constants_are_reused() {
+        let mut chunk = Chunk::new();
+        assert_eq!(chunk.add_constant(Constant::Number(1.0)), 0);
+        assert_eq!(chunk.add_constant(Constant::String("a".into())), 1);
+        assert_eq!(chunk.add_constant(Constant::Number(1.0)), 0);
+        assert_eq!(chunk.constants.len(), 2);
+    }
+}
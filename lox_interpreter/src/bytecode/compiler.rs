@@ -0,0 +1,703 @@
+use std::rc::Rc;
+
+use super::chunk::{Chunk, Constant, Function, OpCode};
+use crate::core::errors::LoxError;
+use crate::core::location::{Location, Span};
+use crate::parser::{Expr, FunctionDecl, Literal, Stmt};
+use crate::tokens::{Token, TokenType};
+
+// Local slots and upvalue indices are single byte operands.
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct Local {
+    name: String,
+    depth: usize,
+    // Whether a closure captures the local, in which case it has to be moved off the stack into
+    // its upvalue when it goes out of scope.
+    captured: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct UpvalueRef {
+    index: u8,
+    // Whether the upvalue captures a local of the enclosing function, or one of its upvalues.
+    is_local: bool,
+}
+
+/// The jumps out of a loop that `break` and `continue` emit, which are patched once the loop's
+/// end and increment are known.
+struct Loop {
+    scope_depth: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+/// What the compiler knows about a function while compiling its body.
+struct FunctionState {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+}
+
+impl FunctionState {
+    fn new(name: &str, filename: &str, kind: FunctionKind) -> Self {
+        // Slot zero holds the function being called, or the instance for methods, which is how
+        // `this` is found.
+        let receiver = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Script | FunctionKind::Function => "",
+        };
+        FunctionState {
+            function: Function::new(name, filename),
+            kind,
+            locals: vec![Local {
+                name: receiver.to_string(),
+                depth: 0,
+                captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+        }
+    }
+}
+
+/// Compiles resolved statements to bytecode. The compiler finds local variables and upvalues
+/// itself, so it relies on the resolver only to have rejected programs that use `this`,
+/// `super` or `return` where they aren't allowed.
+pub struct Compiler {
+    filename: String,
+    functions: Vec<FunctionState>,
+    // The span of source the instructions being emitted come from.
+    span: Span,
+    errors: Vec<LoxError>,
+}
+
+/// Compiles a script to the function that runs its top level code.
+pub fn compile(statements: &[Stmt], filename: &str) -> Result<Function, Vec<LoxError>> {
+    Compiler::new(filename).compile(statements)
+}
+
+impl Compiler {
+    pub fn new(filename: &str) -> Self {
+        Compiler {
+            filename: filename.to_string(),
+            functions: Vec::new(),
+            span: Span::new(0, 0, 1, 1),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn compile(mut self, statements: &[Stmt]) -> Result<Function, Vec<LoxError>> {
+        self.functions
+            .push(FunctionState::new("", &self.filename, FunctionKind::Script));
+        for stmt in statements {
+            self.statement(stmt);
+        }
+        let (function, _) = self.end_function();
+        match self.errors.is_empty() {
+            true => Ok(function),
+            false => Err(self.errors),
+        }
+    }
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions
+            .last_mut()
+            .expect("The compiler always has a function.")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().function.chunk
+    }
+
+    /// Makes the instructions emitted next belong to the location's span.
+    fn at(&mut self, location: &Location) {
+        if let Some(span) = location.span() {
+            self.span = *span;
+        }
+    }
+
+    fn error(&mut self, message: &str) {
+        let location = Location::new_span(self.filename.clone(), self.span);
+        self.errors
+            .push(LoxError::SyntaxError(location, message.to_string()));
+    }
+
+    fn emit(&mut self, op: OpCode) {
+        let span = self.span;
+        self.chunk().write_op(op, span);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let span = self.span;
+        self.chunk().write(byte, span);
+    }
+
+    fn emit_u16(&mut self, value: u16) {
+        for byte in value.to_be_bytes() {
+            self.emit_byte(byte);
+        }
+    }
+
+    fn make_constant(&mut self, constant: Constant) -> u16 {
+        let index = self.chunk().add_constant(constant);
+        u16::try_from(index).unwrap_or_else(|_| {
+            self.error("Too many constants in one chunk.");
+            0
+        })
+    }
+
+    fn emit_constant(&mut self, constant: Constant) {
+        let index = self.make_constant(constant);
+        self.emit(OpCode::Constant);
+        self.emit_u16(index);
+    }
+
+    fn name_constant(&mut self, name: &Token) -> u16 {
+        self.make_constant(Constant::String(Rc::from(name.token_type.to_string())))
+    }
+
+    /// Emits a forward jump with a placeholder offset and returns where the offset is, for
+    /// `patch_jump` to fill in.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit(op);
+        self.emit_u16(u16::MAX);
+        self.chunk().code.len() - 2
+    }
+
+    /// Points the jump at the next instruction to be emitted.
+    fn patch_jump(&mut self, offset: usize) {
+        let jump = self.chunk().code.len() - offset - 2;
+        let jump = u16::try_from(jump).unwrap_or_else(|_| {
+            self.error("Too much code to jump over.");
+            0
+        });
+        self.chunk().code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes());
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit(OpCode::Loop);
+        let offset = self.chunk().code.len() - loop_start + 2;
+        let offset = u16::try_from(offset).unwrap_or_else(|_| {
+            self.error("Loop body too large.");
+            0
+        });
+        self.emit_u16(offset);
+    }
+
+    fn emit_return(&mut self) {
+        match self.current().kind {
+            FunctionKind::Initializer => {
+                self.emit(OpCode::GetLocal);
+                self.emit_byte(0);
+            }
+            _ => self.emit(OpCode::Nil),
+        }
+        self.emit(OpCode::Return);
+    }
+
+    fn end_function(&mut self) -> (Function, Vec<UpvalueRef>) {
+        self.emit_return();
+        let mut state = self.functions.pop().expect("The compiler always has a function.");
+        state.function.upvalue_count = state.upvalues.len();
+        (state.function, state.upvalues)
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.current();
+        state.scope_depth -= 1;
+        let depth = state.scope_depth;
+        while let Some(local) = self.current().locals.pop_if(|local| local.depth > depth) {
+            match local.captured {
+                true => self.emit(OpCode::CloseUpvalue),
+                false => self.emit(OpCode::Pop),
+            }
+        }
+    }
+
+    /// Emits the instructions that discard the locals deeper than the depth without forgetting
+    /// them, for jumping out of the scopes that declare them.
+    fn discard_locals(&mut self, depth: usize) {
+        let ops: Vec<OpCode> = self
+            .current()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| match local.captured {
+                true => OpCode::CloseUpvalue,
+                false => OpCode::Pop,
+            })
+            .collect();
+        for op in ops {
+            self.emit(op);
+        }
+    }
+
+    fn add_local(&mut self, name: &str) {
+        if self.current().locals.len() == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        let state = self.current();
+        let depth = state.scope_depth;
+        state.locals.push(Local {
+            name: name.to_string(),
+            depth,
+            captured: false,
+        });
+    }
+
+    /// Defines a variable whose value is on top of the stack. Inside a scope the value simply
+    /// stays where it is as a new local.
+    fn define_variable(&mut self, name: &Token) {
+        match self.current().scope_depth {
+            0 => {
+                let index = self.name_constant(name);
+                self.emit(OpCode::DefineGlobal);
+                self.emit_u16(index);
+            }
+            _ => self.add_local(&name.token_type.to_string()),
+        }
+    }
+
+    fn resolve_local(&self, function: usize, name: &str) -> Option<u8> {
+        self.functions[function]
+            .locals
+            .iter()
+            .rposition(|local| local.name == name)
+            .map(|slot| slot as u8)
+    }
+
+    fn resolve_upvalue(&mut self, function: usize, name: &str) -> Option<u8> {
+        if function == 0 {
+            return None;
+        }
+        if let Some(slot) = self.resolve_local(function - 1, name) {
+            self.functions[function - 1].locals[slot as usize].captured = true;
+            return self.add_upvalue(function, slot, true);
+        }
+        let index = self.resolve_upvalue(function - 1, name)?;
+        self.add_upvalue(function, index, false)
+    }
+
+    fn add_upvalue(&mut self, function: usize, index: u8, is_local: bool) -> Option<u8> {
+        let upvalue = UpvalueRef { index, is_local };
+        let upvalues = &self.functions[function].upvalues;
+        if let Some(existing) = upvalues.iter().position(|known| *known == upvalue) {
+            return Some(existing as u8);
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            self.error("Too many closure variables in function.");
+            return Some(0);
+        }
+        self.functions[function].upvalues.push(upvalue);
+        Some((self.functions[function].upvalues.len() - 1) as u8)
+    }
+
+    fn get_variable(&mut self, name: &str, token: &Token) {
+        let function = self.functions.len() - 1;
+        if let Some(slot) = self.resolve_local(function, name) {
+            self.emit(OpCode::GetLocal);
+            self.emit_byte(slot);
+        } else if let Some(index) = self.resolve_upvalue(function, name) {
+            self.emit(OpCode::GetUpvalue);
+            self.emit_byte(index);
+        } else {
+            let index = self.name_constant(token);
+            self.emit(OpCode::GetGlobal);
+            self.emit_u16(index);
+        }
+    }
+
+    fn set_variable(&mut self, name: &str, token: &Token) {
+        let function = self.functions.len() - 1;
+        if let Some(slot) = self.resolve_local(function, name) {
+            self.emit(OpCode::SetLocal);
+            self.emit_byte(slot);
+        } else if let Some(index) = self.resolve_upvalue(function, name) {
+            self.emit(OpCode::SetUpvalue);
+            self.emit_byte(index);
+        } else {
+            let index = self.name_constant(token);
+            self.emit(OpCode::SetGlobal);
+            self.emit_u16(index);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.expression(expr);
+                self.emit(OpCode::Pop);
+            }
+            Stmt::Print(expr) => {
+                self.expression(expr);
+                self.emit(OpCode::Print);
+            }
+            Stmt::Var(name, initializer) => {
+                self.expression(initializer);
+                self.at(&name.location);
+                self.define_variable(name);
+            }
+            Stmt::Block(statements) => {
+                self.begin_scope();
+                for stmt in statements {
+                    self.statement(stmt);
+                }
+                self.end_scope();
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit(OpCode::Pop);
+                self.statement(then_branch);
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            Stmt::While(condition, body, increment) => self.while_statement(condition, body, increment),
+            Stmt::Break(keyword) | Stmt::Continue(keyword) => {
+                self.at(&keyword.location);
+                let depth = match self.current().loops.last() {
+                    Some(innermost) => innermost.scope_depth,
+                    None => return self.error("Can't use loop control outside of a loop."),
+                };
+                self.discard_locals(depth);
+                let jump = self.emit_jump(OpCode::Jump);
+                let innermost = self.current().loops.last_mut().expect("Checked above.");
+                match keyword.token_type {
+                    TokenType::Break => innermost.breaks.push(jump),
+                    _ => innermost.continues.push(jump),
+                }
+            }
+            Stmt::Function(declaration) => {
+                self.at(&declaration.name.location);
+                // A local function is declared before its body is compiled, so it can call itself.
+                if self.current().scope_depth > 0 {
+                    self.add_local(&declaration.name.token_type.to_string());
+                    self.function(declaration, FunctionKind::Function);
+                } else {
+                    self.function(declaration, FunctionKind::Function);
+                    self.define_variable(&declaration.name);
+                }
+            }
+            Stmt::Return(keyword, value) => {
+                self.at(&keyword.location);
+                match value {
+                    Some(value) => {
+                        self.expression(value);
+                        self.emit(OpCode::Return);
+                    }
+                    None => self.emit_return(),
+                }
+            }
+            Stmt::Class(name, superclass, methods) => self.class_declaration(name, superclass, methods),
+        }
+    }
+
+    fn while_statement(&mut self, condition: &Expr, body: &Stmt, increment: &Option<Expr>) {
+        let loop_start = self.chunk().code.len();
+        self.expression(condition);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+
+        let scope_depth = self.current().scope_depth;
+        self.current().loops.push(Loop {
+            scope_depth,
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+        self.statement(body);
+        let innermost = self.current().loops.pop().expect("The loop was pushed above.");
+
+        for jump in innermost.continues {
+            self.patch_jump(jump);
+        }
+        if let Some(increment) = increment {
+            self.expression(increment);
+            self.emit(OpCode::Pop);
+        }
+        self.emit_loop(loop_start);
+        self.patch_jump(exit_jump);
+        self.emit(OpCode::Pop);
+        // A `break` has already popped the condition, so it lands after the exit's pop.
+        for jump in innermost.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    /// Compiles the function's body to its own chunk and emits the instruction that makes a
+    /// closure of it, followed by where each of its upvalues is captured from.
+    fn function(&mut self, declaration: &FunctionDecl, kind: FunctionKind) {
+        let name = declaration.name.token_type.to_string();
+        self.functions
+            .push(FunctionState::new(&name, &self.filename, kind));
+        self.current().function.arity = declaration.params.len();
+        self.begin_scope();
+        for param in &declaration.params {
+            self.add_local(&param.token_type.to_string());
+        }
+        for stmt in &declaration.body {
+            self.statement(stmt);
+        }
+        let (function, upvalues) = self.end_function();
+
+        self.at(&declaration.name.location);
+        let index = self.make_constant(Constant::Function(Rc::new(function)));
+        self.emit(OpCode::Closure);
+        self.emit_u16(index);
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn class_declaration(&mut self, name: &Token, superclass: &Option<Expr>, methods: &[Rc<FunctionDecl>]) {
+        self.at(&name.location);
+        let name_constant = self.name_constant(name);
+        self.emit(OpCode::Class);
+        self.emit_u16(name_constant);
+        self.define_variable(name);
+
+        // Methods of a subclass find the superclass in a local named `super` that they capture.
+        if let Some(superclass) = superclass {
+            self.expression(superclass);
+            self.begin_scope();
+            self.add_local("super");
+            self.get_variable(&name.token_type.to_string(), name);
+            self.emit(OpCode::Inherit);
+        }
+
+        self.get_variable(&name.token_type.to_string(), name);
+        for method in methods {
+            let kind = match method.name.token_type.to_string().as_str() {
+                "init" => FunctionKind::Initializer,
+                _ => FunctionKind::Method,
+            };
+            self.function(method, kind);
+            let method_constant = self.name_constant(&method.name);
+            self.emit(OpCode::Method);
+            self.emit_u16(method_constant);
+        }
+        self.emit(OpCode::Pop);
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(location, literal) => {
+                self.at(location);
+                match literal {
+                    Literal::Nil => self.emit(OpCode::Nil),
+                    Literal::True => self.emit(OpCode::True),
+                    Literal::False => self.emit(OpCode::False),
+                    Literal::Number(number) => self.emit_constant(Constant::Number(*number)),
+                    Literal::String(string) => {
+                        self.emit_constant(Constant::String(Rc::from(string.as_str())))
+                    }
+                }
+            }
+            Expr::Grouping(expr) => self.expression(expr),
+            Expr::Unary(operator, operand) => {
+                self.expression(operand);
+                self.at(&operator.location);
+                match operator.token_type {
+                    TokenType::Minus => self.emit(OpCode::Negate),
+                    _ => self.emit(OpCode::Not),
+                }
+            }
+            Expr::Binary(left, operator, right) => {
+                self.expression(left);
+                self.expression(right);
+                self.at(&operator.location);
+                match operator.token_type {
+                    TokenType::EqualEqual => self.emit(OpCode::Equal),
+                    TokenType::BangEqual => {
+                        self.emit(OpCode::Equal);
+                        self.emit(OpCode::Not);
+                    }
+                    TokenType::Greater => self.emit(OpCode::Greater),
+                    TokenType::GreaterEqual => self.emit(OpCode::GreaterEqual),
+                    TokenType::Less => self.emit(OpCode::Less),
+                    TokenType::LessEqual => self.emit(OpCode::LessEqual),
+                    TokenType::Plus => self.emit(OpCode::Add),
+                    TokenType::Minus => self.emit(OpCode::Subtract),
+                    TokenType::Star => self.emit(OpCode::Multiply),
+                    TokenType::Slash => self.emit(OpCode::Divide),
+                    _ => self.error(&format!(
+                        "Can't compile the binary operator {}.",
+                        operator.token_type
+                    )),
+                }
+            }
+            Expr::Logical(left, operator, right) => {
+                self.expression(left);
+                self.at(&operator.location);
+                match operator.token_type {
+                    TokenType::Or => {
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        let end_jump = self.emit_jump(OpCode::Jump);
+                        self.patch_jump(else_jump);
+                        self.emit(OpCode::Pop);
+                        self.expression(right);
+                        self.patch_jump(end_jump);
+                    }
+                    _ => {
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        self.emit(OpCode::Pop);
+                        self.expression(right);
+                        self.patch_jump(end_jump);
+                    }
+                }
+            }
+            Expr::Ternary(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit(OpCode::Pop);
+                self.expression(then_branch);
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit(OpCode::Pop);
+                self.expression(else_branch);
+                self.patch_jump(else_jump);
+            }
+            Expr::Variable(name, _) => {
+                self.at(&name.location);
+                self.get_variable(&name.token_type.to_string(), name);
+            }
+            Expr::Assign(name, value, _) => {
+                self.expression(value);
+                self.at(&name.location);
+                self.set_variable(&name.token_type.to_string(), name);
+            }
+            Expr::Call(callee, paren, arguments) => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.at(&paren.location);
+                self.emit(OpCode::Call);
+                self.emit_byte(arguments.len() as u8);
+            }
+            Expr::Get(object, name) => {
+                self.expression(object);
+                self.at(&name.location);
+                let index = self.name_constant(name);
+                self.emit(OpCode::GetProperty);
+                self.emit_u16(index);
+            }
+            Expr::Set(object, name, value) => {
+                self.expression(object);
+                self.expression(value);
+                self.at(&name.location);
+                let index = self.name_constant(name);
+                self.emit(OpCode::SetProperty);
+                self.emit_u16(index);
+            }
+            Expr::This(keyword, _) => {
+                self.at(&keyword.location);
+                self.get_variable("this", keyword);
+            }
+            Expr::Super(keyword, method, _) => {
+                self.at(&keyword.location);
+                self.get_variable("this", keyword);
+                self.get_variable("super", keyword);
+                self.at(&method.location);
+                let index = self.name_constant(method);
+                self.emit(OpCode::GetSuper);
+                self.emit_u16(index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runhelpers::raw_source_to_ast;
+
+    fn compile_source(source: &str) -> Function {
+        let statements = raw_source_to_ast(source, "unittest.lox").unwrap();
+        compile(&statements, "unittest.lox").unwrap()
+    }
+
+    #[test]
+    fn test_compile_expression_statement() {
+        let function = compile_source("print 1 + 2;");
+        let code: Vec<u8> = vec![
+            OpCode::Constant as u8,
+            0,
+            0,
+            OpCode::Constant as u8,
+            0,
+            1,
+            OpCode::Add as u8,
+            OpCode::Print as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(function.chunk.code, code);
+        assert_eq!(
+            function.chunk.constants,
+            vec![Constant::Number(1.0), Constant::Number(2.0)]
+        );
+    }
+
+    #[test]
+    fn test_locals_live_in_stack_slots() {
+        let function = compile_source("{ var a = 1; print a; }");
+        let code: Vec<u8> = vec![
+            OpCode::Constant as u8,
+            0,
+            0,
+            OpCode::GetLocal as u8,
+            1,
+            OpCode::Print as u8,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(function.chunk.code, code);
+    }
+
+    #[test]
+    fn test_closures_capture_upvalues() {
+        let function = compile_source("fun outer() { var x = 1; fun inner() { return x; } return inner; }");
+        let outer = match &function.chunk.constants[0] {
+            Constant::Function(outer) => outer,
+            other => panic!("Expected a function and got {:?}", other),
+        };
+        let inner = match &outer.chunk.constants[1] {
+            Constant::Function(inner) => inner,
+            other => panic!("Expected a function and got {:?}", other),
+        };
+        assert_eq!(inner.name, "inner");
+        assert_eq!(inner.upvalue_count, 1);
+        assert_eq!(&inner.chunk.code[..2], &[OpCode::GetUpvalue as u8, 0]);
+    }
+}
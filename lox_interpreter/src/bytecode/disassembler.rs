@@ -0,0 +1,174 @@
+use std::fmt::Write;
+
+use super::chunk::{Chunk, Constant, Function, OpCode};
+
+/// Lists the instructions of a compiled function and then of every function it contains, one
+/// chunk after another. When the source is given, each source line is shown above the
+/// instructions compiled from it.
+pub fn disassemble(function: &Function, source: Option<&str>) -> String {
+    let mut out = String::new();
+    disassemble_function(function, source, &mut out);
+    out
+}
+
+fn disassemble_function(function: &Function, source: Option<&str>, out: &mut String) {
+    let _ = writeln!(out, "== {} {} ==", function, function.filename);
+    let chunk = &function.chunk;
+    let mut shown_line = None;
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let line = chunk.line(offset);
+        if let Some(text) = source.and_then(|source| source.lines().nth(line.wrapping_sub(1))) {
+            if shown_line != Some(line) {
+                let _ = writeln!(out, "{:>9} | {}", line, text.trim());
+                shown_line = Some(line);
+            }
+        }
+        offset = disassemble_instruction(chunk, offset, out);
+    }
+    for constant in &chunk.constants {
+        if let Constant::Function(function) = constant {
+            out.push('\n');
+            disassemble_function(function, source, out);
+        }
+    }
+}
+
+/// Writes the instruction at the offset as its offset, source line, opcode and operands, with
+/// the constants they refer to, and returns the offset of the next instruction. The line is
+/// shown as `|` when it is the same as the previous instruction's.
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize, out: &mut String) -> usize {
+    let _ = write!(out, "{:04} ", offset);
+    let line = chunk.line(offset);
+    match offset > 0 && chunk.line(offset - 1) == line {
+        true => out.push_str("   | "),
+        false => {
+            let _ = write!(out, "{:>4} ", line);
+        }
+    }
+
+    let op = match OpCode::try_from(chunk.code[offset]) {
+        Ok(op) => op,
+        Err(byte) => {
+            let _ = writeln!(out, "Unknown opcode {}", byte);
+            return offset + 1;
+        }
+    };
+    let name = format!("{:?}", op);
+    match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Method => {
+            let index = chunk.read_u16(offset + 1);
+            let _ = writeln!(out, "{:<16} {:>4} '{}'", name, index, constant(chunk, index));
+            offset + 3
+        }
+        OpCode::GetLocal | OpCode::SetLocal | OpCode::GetUpvalue | OpCode::SetUpvalue | OpCode::Call => {
+            let _ = writeln!(out, "{:<16} {:>4}", name, chunk.code[offset + 1]);
+            offset + 2
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            let jump = chunk.read_u16(offset + 1) as usize;
+            let target = match op {
+                OpCode::Loop => (offset + 3).wrapping_sub(jump),
+                _ => offset + 3 + jump,
+            };
+            let _ = writeln!(out, "{:<16} {:>4} -> {:04}", name, offset, target);
+            offset + 3
+        }
+        OpCode::Closure => {
+            let index = chunk.read_u16(offset + 1);
+            let _ = writeln!(out, "{:<16} {:>4} {}", name, index, constant(chunk, index));
+            let upvalue_count = match chunk.constants.get(index as usize) {
+                Some(Constant::Function(function)) => function.upvalue_count,
+                _ => 0,
+            };
+            let mut offset = offset + 3;
+            for _ in 0..upvalue_count {
+                let kind = match chunk.code[offset] {
+                    1 => "local",
+                    _ => "upvalue",
+                };
+                let _ = writeln!(
+                    out,
+                    "{:04}    |                     {} {}",
+                    offset,
+                    kind,
+                    chunk.code[offset + 1]
+                );
+                offset += 2;
+            }
+            offset
+        }
+        _ => {
+            let _ = writeln!(out, "{}", name);
+            offset + 1
+        }
+    }
+}
+
+fn constant(chunk: &Chunk, index: u16) -> String {
+    match chunk.constants.get(index as usize) {
+        Some(constant) => constant.to_string(),
+        None => "<missing constant>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compiler::compile;
+    use crate::runhelpers::raw_source_to_ast;
+
+    fn disassemble_source(source: &str, show_source: bool) -> String {
+        let statements = raw_source_to_ast(source, "unittest.lox").unwrap();
+        let function = compile(&statements, "unittest.lox").unwrap();
+        disassemble(&function, show_source.then_some(source))
+    }
+
+    #[test]
+    fn test_disassemble_script() {
+        let expected = "\
+== <script> unittest.lox ==
+0000    1 Constant            0 '1'
+0003    | DefineGlobal        1 'a'
+0006    2 GetGlobal           1 'a'
+0009    | JumpIfFalse         9 -> 0020
+0012    | Pop
+0013    | GetGlobal           1 'a'
+0016    | Print
+0017    | Jump               17 -> 0021
+0020    | Pop
+0021    | Nil
+0022    | Return
+";
+        assert_eq!(disassemble_source("var a = 1;\nif (a) print a;", false), expected);
+    }
+
+    #[test]
+    fn test_disassemble_nested_functions_with_source() {
+        let source = "fun f(x) {\n  return x;\n}";
+        let expected = "\
+== <script> unittest.lox ==
+        1 | fun f(x) {
+0000    1 Closure             0 <fn f>
+0003    | DefineGlobal        1 'f'
+0006    | Nil
+0007    | Return
+
+== <fn f> unittest.lox ==
+        2 | return x;
+0000    2 GetLocal            1
+0002    | Return
+0003    | Nil
+0004    | Return
+";
+        assert_eq!(disassemble_source(source, true), expected);
+    }
+}
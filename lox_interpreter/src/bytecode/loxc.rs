@@ -0,0 +1,630 @@
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+
+use super::chunk::{Chunk, Constant, Function, OpCode, SpanStart};
+use crate::core::errors::LoxError;
+use crate::core::location::{Location, Span};
+
+// The layout of a `.loxc` file, with every number little endian:
+//
+//   magic           b"LOXC"
+//   version         u16
+//   strings         u32 count, then each as a u32 byte length and UTF-8 bytes
+//   script          the function holding the top level code
+//
+// A function is its name and filename as string indices, its arity and upvalue count as u32s,
+// its code as a u32 length and the bytes, its span table as a u32 count of (offset, start, end,
+// line, column) u32 tuples, and its constants as a u32 count of tagged constants. A constant is a tag byte
+// followed by an f64 for a number, a string index for a string, or a whole nested function.
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the layout or the meaning of the instructions changes.
+pub const VERSION: u16 = 2;
+
+const NUMBER_TAG: u8 = 0;
+const STRING_TAG: u8 = 1;
+const FUNCTION_TAG: u8 = 2;
+
+// Functions can only nest as deep as the source does, so anything deeper than this isn't a file
+// we wrote.
+const MAX_NESTING: usize = 256;
+
+/// Serializes a compiled script. Each distinct string is stored once, however many chunks use
+/// it.
+pub fn write(script: &Function) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.function(script);
+
+    let mut bytes = Vec::with_capacity(writer.body.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    write_u32(&mut bytes, writer.strings.len());
+    for string in &writer.strings {
+        write_u32(&mut bytes, string.len());
+        bytes.extend_from_slice(string.as_bytes());
+    }
+    bytes.extend_from_slice(&writer.body);
+    bytes
+}
+
+pub fn write_file(script: &Function, path: &str) -> Result<(), LoxError> {
+    fs::write(path, write(script)).map_err(|err| LoxError::new_syscall(path, 0, err.to_string()))
+}
+
+/// Loads a compiled script, checking the file is complete, is from this version and only holds
+/// instructions the VM can run. `filename` names the file in errors.
+pub fn read(bytes: &[u8], filename: &str) -> Result<Function, LoxError> {
+    let mut reader = Reader {
+        bytes,
+        offset: 0,
+        filename,
+        strings: Vec::new(),
+    };
+    if reader.take(MAGIC.len(), "the file header").ok() != Some(&MAGIC[..]) {
+        return Err(reader.error_at(0, "Not a compiled Lox program."));
+    }
+    let version = reader.u16("the format version")?;
+    if version != VERSION {
+        let message = format!(
+            "Compiled for format version {} but this rlox reads version {}. Recompile the source.",
+            version, VERSION
+        );
+        return Err(reader.error_at(MAGIC.len(), &message));
+    }
+    let count = reader.u32("the string count")?;
+    for _ in 0..count {
+        let length = reader.u32("a string length")?;
+        let start = reader.offset;
+        let string = std::str::from_utf8(reader.take(length, "a string")?)
+            .map_err(|_| reader.error_at(start, "A string isn't valid UTF-8."))?;
+        reader.strings.push(Rc::from(string));
+    }
+    // The script's name and filename come before its arity and upvalue count.
+    let header = reader.offset + 8;
+    let script = reader.function(0)?;
+    if script.arity != 0 {
+        return Err(reader.error_at(header, "The script can't have parameters."));
+    }
+    if script.upvalue_count != 0 {
+        return Err(reader.error_at(header + 4, "The script can't capture variables."));
+    }
+    if reader.offset != bytes.len() {
+        return Err(reader.error("Unexpected data after the end of the program."));
+    }
+    Ok(script)
+}
+
+pub fn read_file(path: &str) -> Result<Function, LoxError> {
+    let bytes = fs::read(path).map_err(|err| LoxError::new_syscall(path, 0, err.to_string()))?;
+    read(&bytes, path)
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+#[derive(Default)]
+struct Writer {
+    strings: Vec<Rc<str>>,
+    indices: HashMap<Rc<str>, usize>,
+    body: Vec<u8>,
+}
+
+impl Writer {
+    fn string(&mut self, string: &str) {
+        let index = match self.indices.get(string) {
+            Some(&index) => index,
+            None => {
+                let string: Rc<str> = Rc::from(string);
+                self.strings.push(Rc::clone(&string));
+                self.indices.insert(string, self.strings.len() - 1);
+                self.strings.len() - 1
+            }
+        };
+        write_u32(&mut self.body, index);
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.string(&function.name);
+        self.string(&function.filename);
+        write_u32(&mut self.body, function.arity);
+        write_u32(&mut self.body, function.upvalue_count);
+
+        let chunk = &function.chunk;
+        write_u32(&mut self.body, chunk.code.len());
+        self.body.extend_from_slice(&chunk.code);
+        write_u32(&mut self.body, chunk.spans.len());
+        for start in &chunk.spans {
+            write_u32(&mut self.body, start.offset);
+            write_u32(&mut self.body, start.span.start);
+            write_u32(&mut self.body, start.span.end);
+            write_u32(&mut self.body, start.span.line);
+            write_u32(&mut self.body, start.span.column);
+        }
+        write_u32(&mut self.body, chunk.constants.len());
+        for constant in &chunk.constants {
+            match constant {
+                Constant::Number(number) => {
+                    self.body.push(NUMBER_TAG);
+                    self.body.extend_from_slice(&number.to_le_bytes());
+                }
+                Constant::String(string) => {
+                    self.body.push(STRING_TAG);
+                    self.string(string);
+                }
+                Constant::Function(function) => {
+                    self.body.push(FUNCTION_TAG);
+                    self.function(function);
+                }
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    filename: &'a str,
+    strings: Vec<Rc<str>>,
+}
+
+impl<'a> Reader<'a> {
+    fn error_at(&self, offset: usize, message: &str) -> LoxError {
+        let location = Location::new_byte(self.filename.to_string(), offset);
+        LoxError::LoadError(location, message.to_string())
+    }
+
+    fn error(&self, message: &str) -> LoxError {
+        self.error_at(self.offset, message)
+    }
+
+    /// The next `length` bytes. `what` says what they were meant to be if the file ends first.
+    fn take(&mut self, length: usize, what: &str) -> Result<&'a [u8], LoxError> {
+        match self.bytes.get(self.offset..self.offset.saturating_add(length)) {
+            Some(bytes) => {
+                self.offset += length;
+                Ok(bytes)
+            }
+            None => Err(self.error(&format!("The file is truncated, it ends in {}.", what))),
+        }
+    }
+
+    fn u8(&mut self, what: &str) -> Result<u8, LoxError> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16, LoxError> {
+        let bytes = self.take(2, what)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self, what: &str) -> Result<usize, LoxError> {
+        let bytes = self.take(4, what)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn string(&mut self, what: &str) -> Result<Rc<str>, LoxError> {
+        let start = self.offset;
+        let index = self.u32(what)?;
+        match self.strings.get(index) {
+            Some(string) => Ok(Rc::clone(string)),
+            None => Err(self.error_at(start, &format!("String {} isn't in the string table.", index))),
+        }
+    }
+
+    fn function(&mut self, depth: usize) -> Result<Function, LoxError> {
+        if depth > MAX_NESTING {
+            return Err(self.error("Functions are nested too deeply."));
+        }
+        let mut function = Function::new(&self.string("a function name")?, &self.string("a filename")?);
+        function.arity = self.u32("an arity")?;
+        function.upvalue_count = self.u32("an upvalue count")?;
+
+        let length = self.u32("a code length")?;
+        let code_start = self.offset;
+        let mut chunk = Chunk::new();
+        chunk.code = self.take(length, "the code")?.to_vec();
+        let count = self.u32("the span table")?;
+        for _ in 0..count {
+            let offset = self.u32("the span table")?;
+            let span = Span::new(
+                self.u32("the span table")?,
+                self.u32("the span table")?,
+                self.u32("the span table")?,
+                self.u32("the span table")?,
+            );
+            chunk.spans.push(SpanStart { offset, span });
+        }
+        let count = self.u32("the constant count")?;
+        for _ in 0..count {
+            let constant = match self.u8("a constant")? {
+                NUMBER_TAG => {
+                    let bytes = self.take(8, "a number")?;
+                    Constant::Number(f64::from_le_bytes(bytes.try_into().unwrap_or_default()))
+                }
+                STRING_TAG => Constant::String(self.string("a string constant")?),
+                FUNCTION_TAG => Constant::Function(Rc::new(self.function(depth + 1)?)),
+                tag => {
+                    let message = format!("Unknown constant tag {}.", tag);
+                    return Err(self.error_at(self.offset - 1, &message));
+                }
+            };
+            chunk.constants.push(constant);
+        }
+        function.chunk = chunk;
+        verify(&function).map_err(|(offset, message)| {
+            self.error_at(code_start + offset, &format!("{} {}", function, message))
+        })?;
+        Ok(function)
+    }
+}
+
+/// Checks that every instruction is whole, that its operands refer to constants, upvalues and
+/// jump targets that exist, and that it only uses values that are on the stack. Returns the
+/// offset of the first bad instruction in the code and what is wrong with it.
+fn verify(function: &Function) -> Result<(), (usize, String)> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let op = OpCode::try_from(code[offset])
+            .map_err(|byte| (offset, format!("has an unknown opcode {}.", byte)))?;
+        instructions.push((offset, op));
+        let operand = |i: usize| {
+            code.get(offset + i)
+                .copied()
+                .ok_or_else(|| (offset, format!("has a {:?} instruction that is cut short.", op)))
+        };
+        let u16_operand =
+            || Ok::<usize, (usize, String)>(u16::from_be_bytes([operand(1)?, operand(2)?]) as usize);
+        let constant = |index: usize| {
+            chunk.constants.get(index).ok_or_else(|| {
+                (
+                    offset,
+                    format!("refers to constant {}, which doesn't exist.", index),
+                )
+            })
+        };
+
+        offset = match op {
+            OpCode::Constant => {
+                if let Constant::Function(_) = constant(u16_operand()?)? {
+                    return Err((offset, "loads a function without making a closure.".to_string()));
+                }
+                offset + 3
+            }
+            OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method => match constant(u16_operand()?)? {
+                Constant::String(_) => offset + 3,
+                _ => {
+                    return Err((
+                        offset,
+                        format!("has a {:?} instruction whose name isn't a string.", op),
+                    ))
+                }
+            },
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                if operand(1)? as usize >= function.upvalue_count {
+                    return Err((
+                        offset,
+                        format!("refers to upvalue {}, which doesn't exist.", operand(1)?),
+                    ));
+                }
+                offset + 2
+            }
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::Call => {
+                operand(1)?;
+                offset + 2
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                let jump = u16_operand()?;
+                let target = match op {
+                    OpCode::Loop => (offset + 3).checked_sub(jump),
+                    _ => Some(offset + 3 + jump),
+                };
+                match target {
+                    Some(target) if target <= code.len() => offset + 3,
+                    _ => return Err((offset, "jumps outside of its code.".to_string())),
+                }
+            }
+            OpCode::Closure => {
+                let upvalue_count = match constant(u16_operand()?)? {
+                    Constant::Function(closed) => closed.upvalue_count,
+                    _ => {
+                        return Err((
+                            offset,
+                            "makes a closure of something that isn't a function.".to_string(),
+                        ))
+                    }
+                };
+                for i in 0..upvalue_count {
+                    let (is_local, index) = (operand(3 + 2 * i)?, operand(4 + 2 * i)?);
+                    if is_local > 1 || (is_local == 0 && index as usize >= function.upvalue_count) {
+                        return Err((offset, "captures an upvalue that doesn't exist.".to_string()));
+                    }
+                }
+                offset + 3 + 2 * upvalue_count
+            }
+            _ => offset + 1,
+        };
+    }
+    match instructions.last() {
+        Some((_, OpCode::Return)) => verify_stack(function, &instructions),
+        _ => Err((code.len(), "doesn't end with a return.".to_string())),
+    }
+}
+
+/// Follows every path through the instructions, which have already been checked to be whole,
+/// keeping track of how many values the function's frame holds. Each instruction has to find
+/// the values it pops and the local slots it uses on the stack, and has to be reached with the
+/// same number of values whichever way the code gets to it.
+fn verify_stack(function: &Function, instructions: &[(usize, OpCode)]) -> Result<(), (usize, String)> {
+    let code = &function.chunk.code;
+    let index_of = |offset: usize, target: usize| {
+        instructions
+            .binary_search_by_key(&target, |&(start, _)| start)
+            .map_err(|_| (offset, "jumps into the middle of an instruction.".to_string()))
+    };
+    // The frame starts with the closure being called and its arguments.
+    let mut depths = vec![None; instructions.len()];
+    let mut pending = vec![(0, function.arity + 1)];
+    while let Some((i, depth)) = pending.pop() {
+        let (offset, op) = instructions[i];
+        match depths[i] {
+            Some(known) if known == depth => continue,
+            Some(_) => return Err((offset, "is reached with different stack depths.".to_string())),
+            None => depths[i] = Some(depth),
+        }
+        let operand = |n: usize| code[offset + n] as usize;
+        let jump = || u16::from_be_bytes([code[offset + 1], code[offset + 2]]) as usize;
+
+        let (pops, pushes) = match op {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::Closure
+            | OpCode::Class => (0, 1),
+            OpCode::Pop | OpCode::DefineGlobal | OpCode::Print | OpCode::CloseUpvalue => (1, 0),
+            OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::JumpIfFalse
+            | OpCode::Return => (1, 1),
+            OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Inherit
+            | OpCode::Method => (2, 1),
+            OpCode::Call => (operand(1) + 1, 1),
+            OpCode::Jump | OpCode::Loop => (0, 0),
+        };
+        if pops > depth {
+            return Err((offset, "pops more values than the stack holds.".to_string()));
+        }
+        let locals: Vec<usize> = match op {
+            OpCode::GetLocal | OpCode::SetLocal => vec![operand(1)],
+            OpCode::Closure => match &function.chunk.constants[jump()] {
+                Constant::Function(closed) => (0..closed.upvalue_count)
+                    .filter(|i| operand(3 + 2 * i) == 1)
+                    .map(|i| operand(4 + 2 * i))
+                    .collect(),
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        if let Some(slot) = locals.into_iter().find(|&slot| slot >= depth) {
+            return Err((
+                offset,
+                format!("refers to local slot {}, which doesn't exist.", slot),
+            ));
+        }
+
+        let depth = depth - pops + pushes;
+        match op {
+            OpCode::Return => (),
+            OpCode::Jump => pending.push((index_of(offset, offset + 3 + jump())?, depth)),
+            OpCode::Loop => pending.push((index_of(offset, offset + 3 - jump())?, depth)),
+            OpCode::JumpIfFalse => {
+                pending.push((index_of(offset, offset + 3 + jump())?, depth));
+                pending.push((i + 1, depth));
+            }
+            _ => pending.push((i + 1, depth)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compiler::compile;
+    use crate::bytecode::vm::Vm;
+    use crate::conformance;
+    use crate::runhelpers::raw_source_to_ast;
+    use rstest::rstest;
+    use std::path::Path;
+
+    const SOURCE: &str = "\
+class Greeter {
+  init(name) { this.name = name; }
+  greet() { fun exclaim() { return this.name + \"!\"; } return exclaim; }
+}
+print Greeter(\"lox\").greet()();
+print 1.5 * 2;";
+
+    fn compiled() -> Function {
+        let statements = raw_source_to_ast(SOURCE, "greeter.lox").unwrap();
+        compile(&statements, "greeter.lox").unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let script = compiled();
+        let bytes = write(&script);
+        assert_eq!(&bytes[..4], MAGIC);
+        let loaded = read(&bytes, "greeter.loxc").unwrap();
+        assert_eq!(loaded, script);
+
+        let mut vm = Vm::new(Vec::new());
+        vm.run(Rc::new(loaded)).unwrap();
+        assert_eq!(vm.writer().as_slice(), b"lox!\n3\n");
+    }
+
+    #[test]
+    fn test_strings_are_stored_once() {
+        let bytes = write(&compiled());
+        let occurrences = bytes
+            .windows(b"greeter.lox".len())
+            .filter(|window| window == b"greeter.lox");
+        assert_eq!(occurrences.count(), 1);
+    }
+
+    #[test]
+    fn test_truncated_files_are_rejected() {
+        let bytes = write(&compiled());
+        for length in 0..bytes.len() {
+            let err = read(&bytes[..length], "greeter.loxc").unwrap_err();
+            assert!(
+                matches!(err, LoxError::LoadError(_, _)),
+                "length {}: {}",
+                length,
+                err
+            );
+        }
+        let err = read(&bytes[..bytes.len() - 1], "greeter.loxc").unwrap_err();
+        assert_eq!(err.message(), "The file is truncated, it ends in a number.");
+    }
+
+    #[test]
+    fn test_wrong_version_is_rejected() {
+        let mut bytes = write(&compiled());
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let err = read(&bytes, "greeter.loxc").unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Compiled for format version 3 but this rlox reads version 2. Recompile the source."
+        );
+        assert_eq!(
+            err.location(),
+            Some(&Location::new_byte("greeter.loxc".to_string(), 4))
+        );
+    }
+
+    #[test]
+    fn test_other_files_are_rejected() {
+        let err = read(SOURCE.as_bytes(), "greeter.lox").unwrap_err();
+        assert_eq!(err.message(), "Not a compiled Lox program.");
+    }
+
+    #[test]
+    fn test_bad_instructions_are_rejected() {
+        let mut script = Function::new("", "bad.lox");
+        let span = Span::new(0, 5, 1, 1);
+        script.chunk.write_op(OpCode::GetGlobal, span);
+        script.chunk.write(0, span);
+        script.chunk.write(7, span);
+        script.chunk.write_op(OpCode::Return, span);
+        let err = read(&write(&script), "bad.loxc").unwrap_err();
+        assert_eq!(
+            err.message(),
+            "<script> refers to constant 7, which doesn't exist."
+        );
+    }
+
+    #[rstest]
+    #[case(2, 0, "The script can't have parameters.")]
+    #[case(0, 1, "The script can't capture variables.")]
+    fn test_scripts_with_parameters_or_upvalues_are_rejected(
+        #[case] arity: usize,
+        #[case] upvalue_count: usize,
+        #[case] message: &str,
+    ) {
+        let mut script = compiled();
+        script.arity = arity;
+        script.upvalue_count = upvalue_count;
+        let bytes = write(&script);
+        let err = read(&bytes, "greeter.loxc").unwrap_err();
+        assert_eq!(err.message(), message);
+        // The error points at the field that is wrong.
+        let Some(Location::Byte(_, offset)) = err.location() else {
+            panic!("Expected a byte offset, got {:?}", err.location());
+        };
+        let field = u32::from_le_bytes(bytes[*offset..offset + 4].try_into().unwrap());
+        assert_eq!(field as usize, arity.max(upvalue_count));
+    }
+
+    fn patched(source: &str, patch: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let statements = raw_source_to_ast(source, "patched.lox").unwrap();
+        let mut script = compile(&statements, "patched.lox").unwrap();
+        patch(&mut script.chunk.code);
+        write(&script)
+    }
+
+    #[test]
+    fn test_locals_that_dont_exist_are_rejected() {
+        let bytes = patched("{ var a = 1; print a; }", |code| {
+            let get = code
+                .iter()
+                .position(|&byte| byte == OpCode::GetLocal as u8)
+                .unwrap();
+            code[get + 1] = 200;
+        });
+        let err = read(&bytes, "patched.loxc").unwrap_err();
+        assert!(matches!(err, LoxError::LoadError(_, _)));
+        assert_eq!(
+            err.message(),
+            "<script> refers to local slot 200, which doesn't exist."
+        );
+    }
+
+    #[test]
+    fn test_popping_an_empty_stack_is_rejected() {
+        let bytes = patched("{ var a = 1; print a; }", |code| {
+            let nil = code.len() - 2;
+            assert_eq!(code[nil], OpCode::Nil as u8);
+            code[nil] = OpCode::Pop as u8;
+        });
+        let err = read(&bytes, "patched.loxc").unwrap_err();
+        assert!(matches!(err, LoxError::LoadError(_, _)));
+        assert_eq!(err.message(), "<script> pops more values than the stack holds.");
+    }
+
+    #[test]
+    fn test_compiled_data_scripts_pass_verification() {
+        let files = conformance::discover(Path::new("./lox_interpreter/data")).unwrap();
+        for file in files {
+            let source = fs::read_to_string(&file).unwrap();
+            let filename = file.display().to_string();
+            let Ok(statements) = raw_source_to_ast(&source, &filename) else {
+                continue;
+            };
+            if let Ok(script) = compile(&statements, &filename) {
+                let loaded = read(&write(&script), &filename);
+                assert_eq!(loaded, Ok(script), "{}", filename);
+            }
+        }
+    }
+}
@@ -0,0 +1,6 @@
+pub mod chunk;
+pub mod compiler;
+pub mod disassembler;
+pub mod loxc;
+pub mod object;
+pub mod vm;
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::rc::Rc;
+use std::time::Instant;
+
+use super::chunk::Function;
+use crate::gc::{GcBudget, GcConfig, GcStats};
+use crate::value::format_number;
+
+/// A handle to an object on the VM's heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+/// A value on the VM's stack. Anything bigger than a number lives on the heap and is referred
+/// to by handle, so values are cheap to copy. Strings are interned, which makes comparing
+/// handles the same as comparing the strings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Object(ObjRef),
+}
+
+impl Value {
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Boolean(false) | Value::Nil => false,
+            _ => true,
+        }
+    }
+}
+
+/// The Rust side of a native function, see `value::NativeFn`.
+pub type NativeFn = fn(&[Value]) -> Result<Value, String>;
+
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+/// A function together with the variables it captured from the functions around it.
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// A captured variable. It is open, pointing at a slot on the stack, for as long as that slot is
+/// live, and is closed over a copy of the value when the slot goes away.
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<Rc<str>, ObjRef>,
+}
+
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: HashMap<Rc<str>, Value>,
+}
+
+/// A method closure with `this` bound to the instance it was read from.
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+pub enum Object {
+    String(Rc<str>),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+}
+
+impl Object {
+    /// An estimate of the bytes the object takes up, including what it owns but not the objects
+    /// it refers to.
+    fn size(&self) -> usize {
+        let owned = match self {
+            Object::String(string) => string.len(),
+            Object::Closure(closure) => closure.upvalues.len() * size_of::<ObjRef>(),
+            Object::Class(class) => class.name.len() + class.methods.len() * size_of::<(Rc<str>, ObjRef)>(),
+            Object::Instance(instance) => instance.fields.len() * size_of::<(Rc<str>, Value)>(),
+            Object::Native(native) => native.name.len(),
+            Object::Upvalue(_) | Object::BoundMethod(_) => 0,
+        };
+        size_of::<Object>() + owned
+    }
+
+    /// Calls `visit` with every value the object refers to.
+    fn trace(&self, mut visit: impl FnMut(Value)) {
+        match self {
+            Object::Closure(closure) => closure
+                .upvalues
+                .iter()
+                .for_each(|&upvalue| visit(Value::Object(upvalue))),
+            Object::Upvalue(Upvalue::Closed(value)) => visit(*value),
+            Object::Class(class) => class
+                .methods
+                .values()
+                .for_each(|&method| visit(Value::Object(method))),
+            Object::Instance(instance) => {
+                visit(Value::Object(instance.class));
+                instance.fields.values().for_each(|&value| visit(value));
+            }
+            Object::BoundMethod(bound) => {
+                visit(bound.receiver);
+                visit(Value::Object(bound.method));
+            }
+            Object::String(_) | Object::Upvalue(Upvalue::Open(_)) | Object::Native(_) => (),
+        }
+    }
+}
+
+/// Where the VM's objects live. The heap only allocates: it is up to the VM to call `collect`
+/// with its roots, which it does whenever `should_collect` says the heap has grown enough. The
+/// slots of collected objects are reused, so a handle to an object that was wrongly collected
+/// ends up pointing at whatever is allocated next.
+#[derive(Default)]
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    // Slots whose objects have been collected, to be reused first.
+    free: Vec<usize>,
+    strings: HashMap<Rc<str>, ObjRef>,
+    budget: GcBudget,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap::default()
+    }
+
+    pub fn config(&self) -> GcConfig {
+        self.budget.config()
+    }
+
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.budget.set_config(config);
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.budget.stats(self.len())
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.budget.should_collect()
+    }
+
+    /// An estimate of the bytes allocated, as of the last collection plus what has been
+    /// allocated since.
+    pub fn bytes_allocated(&self) -> usize {
+        self.budget.bytes_allocated()
+    }
+
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        self.budget.allocated(object.size());
+        match self.free.pop() {
+            Some(slot) => {
+                self.objects[slot] = Some(object);
+                ObjRef(slot)
+            }
+            None => {
+                self.objects.push(Some(object));
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    /// The handle of the string, allocating it the first time it is seen.
+    pub fn intern(&mut self, string: &str) -> ObjRef {
+        if let Some(&reference) = self.strings.get(string) {
+            return reference;
+        }
+        let string: Rc<str> = Rc::from(string);
+        let reference = self.alloc(Object::String(Rc::clone(&string)));
+        self.strings.insert(string, reference);
+        reference
+    }
+
+    pub fn get(&self, reference: ObjRef) -> &Object {
+        self.objects[reference.0]
+            .as_ref()
+            .expect("Objects in use are never collected.")
+    }
+
+    pub fn get_mut(&mut self, reference: ObjRef) -> &mut Object {
+        self.objects[reference.0]
+            .as_mut()
+            .expect("Objects in use are never collected.")
+    }
+
+    /// How many objects are live, including garbage that hasn't been collected yet.
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Marks everything reachable from the roots and frees the rest. Interned strings don't
+    /// keep themselves alive, so a string nothing refers to is dropped from the table too.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) {
+        let start = Instant::now();
+        let mut marked = vec![false; self.objects.len()];
+        let mut gray = Vec::new();
+        for root in roots {
+            mark(root, &mut marked, &mut gray);
+        }
+        while let Some(reference) = gray.pop() {
+            self.get(reference)
+                .trace(|value| mark(value, &mut marked, &mut gray));
+        }
+
+        self.strings.retain(|_, reference| marked[reference.0]);
+        let mut live_bytes = 0;
+        let mut freed = 0;
+        for (slot, object) in self.objects.iter_mut().enumerate() {
+            match (object.as_ref(), marked[slot]) {
+                (Some(live), true) => live_bytes += live.size(),
+                (Some(_), false) => {
+                    *object = None;
+                    self.free.push(slot);
+                    freed += 1;
+                }
+                (None, _) => (),
+            }
+        }
+        self.budget.collected(live_bytes, freed, start.elapsed());
+    }
+
+    pub fn string(&self, value: Value) -> Option<&Rc<str>> {
+        match value {
+            Value::Object(reference) => match self.get(reference) {
+                Object::String(string) => Some(string),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The value as `print` shows it, matching how the tree-walking interpreter shows the same
+    /// value.
+    pub fn format(&self, value: Value) -> String {
+        match value {
+            Value::Nil => "nil".to_string(),
+            Value::Boolean(boolean) => boolean.to_string(),
+            Value::Number(number) => format_number(number),
+            Value::Object(reference) => match self.get(reference) {
+                Object::String(string) => string.to_string(),
+                Object::Closure(closure) => closure.function.to_string(),
+                Object::Upvalue(_) => "upvalue".to_string(),
+                Object::Class(class) => class.name.clone(),
+                Object::Instance(instance) => format!("{} instance", self.class_name(instance.class)),
+                Object::BoundMethod(bound) => self.format(Value::Object(bound.method)),
+                Object::Native(_) => "<native fn>".to_string(),
+            },
+        }
+    }
+
+    /// The value as the tree-walking interpreter's `Debug` output shows it, so both backends
+    /// report type errors with the same message.
+    pub fn describe(&self, value: Value) -> String {
+        match value {
+            Value::Nil => "Nil".to_string(),
+            Value::Boolean(boolean) => format!("Boolean({})", boolean),
+            Value::Number(number) => format!("Number({:?})", number),
+            Value::Object(reference) => match self.get(reference) {
+                Object::String(string) => format!("String({:?})", string),
+                Object::Closure(closure) => format!("Function(LoxFunction({}))", closure.function.name),
+                Object::Upvalue(_) => "Upvalue".to_string(),
+                Object::Class(class) => format!("Class(LoxClass({}))", class.name),
+                Object::Instance(instance) => {
+                    format!("Instance(LoxInstance({}))", self.class_name(instance.class))
+                }
+                Object::BoundMethod(bound) => self.describe(Value::Object(bound.method)),
+                Object::Native(native) => format!("NativeFunction(NativeFunction({}))", native.name),
+            },
+        }
+    }
+
+    fn class_name(&self, class: ObjRef) -> &str {
+        match self.get(class) {
+            Object::Class(class) => &class.name,
+            _ => "",
+        }
+    }
+}
+
+fn mark(value: Value, marked: &mut [bool], gray: &mut Vec<ObjRef>) {
+    if let Value::Object(reference) = value {
+        if !marked[reference.0] {
+            marked[reference.0] = true;
+            gray.push(reference);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strings_are_interned() {
+        let mut heap = Heap::new();
+        let a = heap.intern("lox");
+        let b = heap.intern("lox");
+        let c = heap.intern("other");
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(heap.len(), 2);
+        assert_eq!(heap.format(Value::Object(a)), "lox");
+        assert_eq!(heap.describe(Value::Object(a)), "String(\"lox\")");
+    }
+
+    #[test]
+    fn test_collect_frees_what_the_roots_cant_reach() {
+        let mut heap = Heap::new();
+        let kept = heap.intern("kept");
+        let class = heap.alloc(Object::Class(Class {
+            name: "A".to_string(),
+            methods: HashMap::new(),
+        }));
+        let mut fields = HashMap::new();
+        fields.insert(Rc::from("name"), Value::Object(kept));
+        let instance = heap.alloc(Object::Instance(Instance { class, fields }));
+        heap.intern("dropped");
+        assert_eq!(heap.len(), 4);
+
+        heap.collect([Value::Object(instance)]);
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.format(Value::Object(instance)), "A instance");
+        // The slot freed by the collected string is reused, and the string is interned afresh.
+        let dropped = heap.intern("dropped");
+        assert_eq!(heap.len(), 4);
+        assert_eq!(heap.format(Value::Object(dropped)), "dropped");
+        assert_eq!(heap.intern("kept"), kept);
+    }
+}
@@ -0,0 +1,838 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+use super::chunk::{Constant, Function, OpCode};
+use super::compiler;
+use super::disassembler::disassemble_instruction;
+use super::object::{
+    BoundMethod, Class, Closure, Heap, Instance, Native, NativeFn, ObjRef, Object, Upvalue, Value,
+};
+use crate::core::errors::LoxError;
+use crate::core::location::Location;
+use crate::natives;
+use crate::parser::Parser;
+use crate::resolver::Resolver;
+use crate::scanner::SourceCode;
+
+// How deep calls can nest before the script is stopped with a stack overflow.
+const FRAMES_MAX: usize = 1024;
+
+/// A function call in progress. Its locals start at `slots` on the value stack, where the
+/// callee itself, or the instance for a method, sits in slot zero.
+struct CallFrame {
+    closure: ObjRef,
+    function: Rc<Function>,
+    ip: usize,
+    slots: usize,
+}
+
+/// A stack based virtual machine that runs compiled functions. Like `InterpreterState`, it
+/// keeps its globals between runs and prints to its writer.
+pub struct Vm<W: Write> {
+    writer: W,
+    heap: Heap,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<Rc<str>, Value>,
+    // The upvalues still pointing at stack slots, which are closed when those slots go away.
+    open_upvalues: Vec<ObjRef>,
+    trace: bool,
+}
+
+impl Default for Vm<std::io::Stdout> {
+    fn default() -> Self {
+        Vm::new(std::io::stdout())
+    }
+}
+
+impl<W: Write> Vm<W> {
+    /// A VM whose globals hold just the built-in native functions.
+    pub fn new(writer: W) -> Self {
+        let mut vm = Vm {
+            writer,
+            heap: Heap::new(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            trace: false,
+        };
+        vm.define_native("clock", 0, clock);
+        vm
+    }
+
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = Native {
+            name: name.to_string(),
+            arity,
+            function,
+        };
+        let native = self.alloc(Object::Native(native));
+        self.globals.insert(Rc::from(name), Value::Object(native));
+    }
+
+    /// Turns execution tracing on or off. While it is on, the stack and the instruction about to
+    /// run are written out before each instruction, interleaved with what the script prints.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn writer(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// Scans, parses, resolves and compiles the source and then runs it. Nothing runs if any of
+    /// those steps find errors, and the script stops at the first runtime error.
+    pub fn run_source(&mut self, source: &str, filename: &str) -> Result<(), Vec<LoxError>> {
+        let tokens = SourceCode::new(source, filename.to_string()).scan_tokens();
+        let (statements, errors) = Parser::new(tokens).parse_recovering();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let errors = Resolver::new().resolve(&statements);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let function = compiler::compile(&statements, filename)?;
+        self.run(Rc::new(function)).map_err(|err| vec![err])
+    }
+
+    /// Runs a compiled script.
+    pub fn run(&mut self, function: Rc<Function>) -> Result<(), LoxError> {
+        // The script isn't called from anywhere, so a problem with it can't be reported from
+        // inside a call like `call` does.
+        if function.arity != 0 || function.upvalue_count != 0 {
+            let location = Location::new_line(function.filename.clone(), 0);
+            return Err(LoxError::RuntimeError(
+                location,
+                format!("Can't run {} as a script.", function),
+            ));
+        }
+        let closure = self.alloc(Object::Closure(Closure {
+            function: Rc::clone(&function),
+            upvalues: Vec::new(),
+        }));
+        self.stack.push(Value::Object(closure));
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - 1,
+        });
+        let result = self.execute();
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        result
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("The VM only executes inside a call.")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .expect("The VM only executes inside a call.")
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let frame = self.frame_mut();
+        let value = frame.function.chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    fn read_constant(&mut self) -> Constant {
+        let index = self.read_u16() as usize;
+        self.frame().function.chunk.constants[index].clone()
+    }
+
+    fn read_name(&mut self) -> Rc<str> {
+        match self.read_constant() {
+            Constant::String(name) => name,
+            other => panic!("Expected a name constant and got {:?}", other),
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("The compiler keeps the stack balanced.")
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    /// A runtime error at the instruction being executed. Each call the error happened inside
+    /// adds a line to its stack trace, like in the tree-walking interpreter.
+    fn error(&self, message: &str) -> LoxError {
+        let location = |frame: &CallFrame| {
+            let filename = frame.function.filename.clone();
+            match frame.function.chunk.span(frame.ip.saturating_sub(1)) {
+                Some(span) => Location::new_span(filename, span),
+                None => Location::new_line(filename, 0),
+            }
+        };
+        let mut err = LoxError::RuntimeError(location(self.frame()), message.to_string());
+        for (callee, caller) in self.frames.iter().rev().zip(self.frames.iter().rev().skip(1)) {
+            let call_site = location(caller).position().unwrap_or_default();
+            err = err.with_frame(&format!("in {}(), called at {}", callee.function.name, call_site));
+        }
+        err
+    }
+
+    fn execute(&mut self) -> Result<(), LoxError> {
+        loop {
+            if self.trace {
+                self.trace_instruction()?;
+            }
+            let byte = self.read_byte();
+            let op =
+                OpCode::try_from(byte).map_err(|byte| self.error(&format!("Unknown opcode {}.", byte)))?;
+            match op {
+                OpCode::Constant => {
+                    let value = match self.read_constant() {
+                        Constant::Number(number) => Value::Number(number),
+                        Constant::String(string) => Value::Object(self.intern(&string)),
+                        Constant::Function(function) => {
+                            return Err(self.error(&format!("Can't load {} without a closure.", function)))
+                        }
+                    };
+                    self.push(value);
+                }
+                OpCode::Nil => self.push(Value::Nil),
+                OpCode::True => self.push(Value::Boolean(true)),
+                OpCode::False => self.push(Value::Boolean(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.push(self.stack[slot]);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack[slot] = self.peek(0);
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_name();
+                    match self.globals.get(&name) {
+                        Some(&value) => self.push(value),
+                        None => return Err(self.error(&format!("Undefined variable: {}", name))),
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_name();
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_name();
+                    let value = self.peek(0);
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => return Err(self.error(&format!("Undefined variable: {}", name))),
+                    }
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte();
+                    let upvalue = self.upvalue(index);
+                    let value = match self.heap.get(upvalue) {
+                        Object::Upvalue(Upvalue::Open(slot)) => self.stack[*slot],
+                        Object::Upvalue(Upvalue::Closed(value)) => *value,
+                        _ => Value::Nil,
+                    };
+                    self.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte();
+                    let upvalue = self.upvalue(index);
+                    let value = self.peek(0);
+                    match self.heap.get_mut(upvalue) {
+                        Object::Upvalue(Upvalue::Open(slot)) => {
+                            let slot = *slot;
+                            self.stack[slot] = value;
+                        }
+                        Object::Upvalue(closed) => *closed = Upvalue::Closed(value),
+                        _ => (),
+                    }
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_name();
+                    let instance = match self.peek(0) {
+                        Value::Object(reference) => match self.heap.get(reference) {
+                            Object::Instance(instance) => Some(instance),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    let Some(instance) = instance else {
+                        return Err(self.error("Only instances have properties."));
+                    };
+                    match instance.fields.get(&name) {
+                        Some(&value) => {
+                            self.pop();
+                            self.push(value);
+                        }
+                        None => {
+                            let class = instance.class;
+                            self.bind_method(class, &name)?;
+                        }
+                    }
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_name();
+                    let value = self.peek(0);
+                    let instance = match self.peek(1) {
+                        Value::Object(reference) => reference,
+                        _ => return Err(self.error("Only instances have fields.")),
+                    };
+                    match self.heap.get_mut(instance) {
+                        Object::Instance(instance) => instance.fields.insert(name, value),
+                        _ => return Err(self.error("Only instances have fields.")),
+                    };
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_name();
+                    let superclass = match self.pop() {
+                        Value::Object(reference) => reference,
+                        _ => {
+                            return Err(self.error("Can't use 'super' outside of a class with a superclass."))
+                        }
+                    };
+                    self.bind_method(superclass, &name)?;
+                }
+                OpCode::Equal => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.push(Value::Boolean(left == right));
+                }
+                OpCode::Greater => self.compare(|left, right| left > right)?,
+                OpCode::GreaterEqual => self.compare(|left, right| left >= right)?,
+                OpCode::Less => self.compare(|left, right| left < right)?,
+                OpCode::LessEqual => self.compare(|left, right| left <= right)?,
+                OpCode::Add => {
+                    let (left, right) = (self.peek(1), self.peek(0));
+                    let value = match (left, right) {
+                        (Value::Number(left), Value::Number(right)) => Value::Number(left + right),
+                        _ => match (self.heap.string(left), self.heap.string(right)) {
+                            (Some(left), Some(right)) => {
+                                // Both strings stay on the stack until the result is pushed, so a
+                                // collection while interning it can't free them.
+                                let concatenated = format!("{}{}", left, right);
+                                Value::Object(self.intern(&concatenated))
+                            }
+                            _ => return Err(self.operand_error(left, right)),
+                        },
+                    };
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::Subtract => self.arithmetic(|left, right| left - right)?,
+                OpCode::Multiply => self.arithmetic(|left, right| left * right)?,
+                OpCode::Divide => self.arithmetic(|left, right| left / right)?,
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.push(Value::Boolean(!value.is_truthy()));
+                }
+                OpCode::Negate => match self.peek(0) {
+                    Value::Number(number) => {
+                        self.pop();
+                        self.push(Value::Number(-number));
+                    }
+                    other => {
+                        let message = format!("Expected Value::Number and got {}", self.heap.describe(other));
+                        return Err(self.error(&message));
+                    }
+                },
+                OpCode::Print => {
+                    let value = self.pop();
+                    let text = self.heap.format(value);
+                    if let Err(err) = writeln!(self.writer, "{}", text) {
+                        return Err(LoxError::new_syscall(
+                            std::file!(),
+                            line!() as usize,
+                            err.to_string(),
+                        ));
+                    }
+                }
+                OpCode::Jump => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_u16() as usize;
+                    if !self.peek(0).is_truthy() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(self.peek(arg_count), arg_count)?;
+                }
+                OpCode::Closure => {
+                    let function = match self.read_constant() {
+                        Constant::Function(function) => function,
+                        other => return Err(self.error(&format!("Can't make a closure of {:?}.", other))),
+                    };
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte();
+                        upvalues.push(match is_local {
+                            true => self.capture_upvalue(self.frame().slots + index as usize),
+                            false => self.upvalue(index),
+                        });
+                    }
+                    // The upvalues are all open or belong to the running closure, so they are
+                    // still reachable while the closure is allocated.
+                    let closure = self.alloc(Object::Closure(Closure { function, upvalues }));
+                    self.push(Value::Object(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("The VM only executes inside a call.");
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                    self.push(result);
+                }
+                OpCode::Class => {
+                    let name = self.read_name();
+                    let class = self.alloc(Object::Class(Class {
+                        name: name.to_string(),
+                        methods: HashMap::new(),
+                    }));
+                    self.push(Value::Object(class));
+                }
+                OpCode::Inherit => {
+                    let methods = match self.peek(1) {
+                        Value::Object(reference) => match self.heap.get(reference) {
+                            Object::Class(superclass) => Some(superclass.methods.clone()),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    let Some(methods) = methods else {
+                        return Err(self.error("Superclass must be a class."));
+                    };
+                    if let Value::Object(subclass) = self.pop() {
+                        if let Object::Class(subclass) = self.heap.get_mut(subclass) {
+                            subclass.methods.extend(methods);
+                        }
+                    }
+                }
+                OpCode::Method => {
+                    let name = self.read_name();
+                    let method = self.pop();
+                    if let (Value::Object(class), Value::Object(method)) = (self.peek(0), method) {
+                        if let Object::Class(class) = self.heap.get_mut(class) {
+                            class.methods.insert(name, method);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn trace_instruction(&mut self) -> Result<(), LoxError> {
+        let mut out = String::from("          ");
+        for &value in &self.stack {
+            out.push_str(&format!("[ {} ]", self.heap.format(value)));
+        }
+        out.push('\n');
+        let frame = self.frame();
+        disassemble_instruction(&frame.function.chunk, frame.ip, &mut out);
+        self.writer
+            .write_all(out.as_bytes())
+            .map_err(|err| LoxError::new_syscall(std::file!(), line!() as usize, err.to_string()))
+    }
+
+    fn operand_error(&self, left: Value, right: Value) -> LoxError {
+        let message = format!(
+            "Expected two numbers and got left: {} -- right: {}",
+            self.heap.describe(left),
+            self.heap.describe(right)
+        );
+        self.error(&message)
+    }
+
+    fn arithmetic(&mut self, operation: fn(f64, f64) -> f64) -> Result<(), LoxError> {
+        match (self.peek(1), self.peek(0)) {
+            (Value::Number(left), Value::Number(right)) => {
+                self.pop();
+                self.pop();
+                self.push(Value::Number(operation(left, right)));
+                Ok(())
+            }
+            (left, right) => Err(self.operand_error(left, right)),
+        }
+    }
+
+    fn compare(&mut self, comparison: fn(f64, f64) -> bool) -> Result<(), LoxError> {
+        match (self.peek(1), self.peek(0)) {
+            (Value::Number(left), Value::Number(right)) => {
+                self.pop();
+                self.pop();
+                self.push(Value::Boolean(comparison(left, right)));
+                Ok(())
+            }
+            (left, right) => Err(self.operand_error(left, right)),
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), LoxError> {
+        let Value::Object(reference) = callee else {
+            return Err(self.error("Can only call functions and classes."));
+        };
+        match self.heap.get(reference) {
+            Object::Closure(closure) => {
+                let function = Rc::clone(&closure.function);
+                self.call(reference, function, arg_count)
+            }
+            Object::BoundMethod(bound) => {
+                let (receiver, method) = (bound.receiver, bound.method);
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = receiver;
+                self.call_value(Value::Object(method), arg_count)
+            }
+            Object::Class(class) => {
+                let initializer = class.methods.get("init").copied();
+                let instance = self.alloc(Object::Instance(Instance {
+                    class: reference,
+                    fields: HashMap::new(),
+                }));
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = Value::Object(instance);
+                match initializer {
+                    Some(initializer) => self.call_value(Value::Object(initializer), arg_count),
+                    None if arg_count != 0 => {
+                        Err(self.error(&format!("Expected 0 arguments but got {}.", arg_count)))
+                    }
+                    None => Ok(()),
+                }
+            }
+            Object::Native(native) => {
+                if arg_count != native.arity {
+                    let message = format!("Expected {} arguments but got {}.", native.arity, arg_count);
+                    return Err(self.error(&message));
+                }
+                let arguments = &self.stack[self.stack.len() - arg_count..];
+                let result = (native.function)(arguments).map_err(|message| self.error(&message))?;
+                self.stack.truncate(self.stack.len() - arg_count - 1);
+                self.push(result);
+                Ok(())
+            }
+            _ => Err(self.error("Can only call functions and classes.")),
+        }
+    }
+
+    fn call(&mut self, closure: ObjRef, function: Rc<Function>, arg_count: usize) -> Result<(), LoxError> {
+        if arg_count != function.arity {
+            let message = format!("Expected {} arguments but got {}.", function.arity, arg_count);
+            return Err(self.error(&message));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.error("Stack overflow."));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    /// Replaces the instance on top of the stack with its class's method bound to it.
+    fn bind_method(&mut self, class: ObjRef, name: &str) -> Result<(), LoxError> {
+        let method = match self.heap.get(class) {
+            Object::Class(class) => class.methods.get(name).copied(),
+            _ => None,
+        };
+        let Some(method) = method else {
+            return Err(self.error(&format!("Undefined property '{}'.", name)));
+        };
+        // The receiver stays on the stack until the bound method replaces it, keeping it and its
+        // class alive if allocating collects.
+        let receiver = self.peek(0);
+        let bound = self.alloc(Object::BoundMethod(BoundMethod { receiver, method }));
+        self.pop();
+        self.push(Value::Object(bound));
+        Ok(())
+    }
+
+    /// Allocates the object, first collecting garbage if the heap has grown enough. Anything
+    /// the object refers to has to be reachable from the roots until it is allocated.
+    fn alloc(&mut self, object: Object) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(object)
+    }
+
+    fn intern(&mut self, string: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(string)
+    }
+
+    /// Collects everything that can't be reached from the stack, the closures being run, the
+    /// open upvalues or the globals.
+    pub fn collect_garbage(&mut self) {
+        let roots = self
+            .stack
+            .iter()
+            .copied()
+            .chain(self.frames.iter().map(|frame| Value::Object(frame.closure)))
+            .chain(self.open_upvalues.iter().map(|&upvalue| Value::Object(upvalue)))
+            .chain(self.globals.values().copied());
+        self.heap.collect(roots);
+    }
+
+    fn upvalue(&self, index: u8) -> ObjRef {
+        match self.heap.get(self.frame().closure) {
+            Object::Closure(closure) => closure.upvalues[index as usize],
+            _ => panic!("Call frames always run closures."),
+        }
+    }
+
+    /// The upvalue for the stack slot, reusing an open one so closures share the variable.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let existing = self.open_upvalues.iter().copied().find(|&upvalue| {
+            matches!(self.heap.get(upvalue), Object::Upvalue(Upvalue::Open(open)) if *open == slot)
+        });
+        if let Some(upvalue) = existing {
+            return upvalue;
+        }
+        let upvalue = self.alloc(Object::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    /// Closes the open upvalues for the slot and every slot above it.
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        let heap = &mut self.heap;
+        self.open_upvalues.retain(|&upvalue| match heap.get_mut(upvalue) {
+            Object::Upvalue(state @ Upvalue::Open(_)) => {
+                let Upvalue::Open(slot) = *state else { return true };
+                match slot >= from {
+                    true => {
+                        *state = Upvalue::Closed(stack[slot]);
+                        false
+                    }
+                    false => true,
+                }
+            }
+            _ => false,
+        });
+    }
+}
+
+fn clock(_arguments: &[Value]) -> Result<Value, String> {
+    natives::seconds_since_epoch().map(Value::Number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::location::Span;
+    use crate::gc::GcConfig;
+    use crate::Lox;
+    use rstest::*;
+
+    fn run(source: &str) -> (String, Result<(), Vec<LoxError>>) {
+        run_with_gc(source, GcConfig::default())
+    }
+
+    fn run_with_gc(source: &str, gc: GcConfig) -> (String, Result<(), Vec<LoxError>>) {
+        let mut vm = Vm::new(Vec::new());
+        vm.heap_mut().set_config(gc);
+        let result = vm.run_source(source, "unittest.lox");
+        (String::from_utf8(vm.writer().clone()).unwrap(), result)
+    }
+
+    #[rstest]
+    #[case("print 1 + 2 * 3;", "7\n")]
+    #[case("print \"a\" + \"b\" == \"ab\";", "true\n")]
+    #[case("var a = 1; { var a = 2; print a; } print a;", "2\n1\n")]
+    #[case("print nil or 1 > 2 ? \"yes\" : \"no\";", "no\n")]
+    #[case(
+        "fun f(n) { if (n < 2) return n; return f(n - 1) + f(n - 2); } print f(10);",
+        "55\n"
+    )]
+    #[case(
+        "fun counter() { var i = 0; fun inc() { i = i + 1; return i; } return inc; } var c = counter(); c(); print c();",
+        "2\n"
+    )]
+    #[case(
+        "class A { init(x) { this.x = x; } get() { return this.x; } } print A(3).get();",
+        "3\n"
+    )]
+    #[case(
+        "class A { m() { return 1; } } class B < A { m() { return super.m() + 1; } } print B().m();",
+        "2\n"
+    )]
+    #[case(
+        "for (var i = 0; i < 5; i = i + 1) { if (i == 1) continue; if (i == 3) break; print i; }",
+        "0\n2\n"
+    )]
+    #[case("print clock;", "<native fn>\n")]
+    fn test_run(#[case] source: &str, #[case] expected: &str) {
+        for gc in [GcConfig::default(), GcConfig::stress()] {
+            let (output, result) = run_with_gc(source, gc);
+            assert_eq!(result, Ok(()));
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_script_with_parameters_is_an_error() {
+        let mut script = Function::new("", "unittest.lox");
+        script.arity = 1;
+        let mut vm = Vm::new(Vec::new());
+        let err = vm.run(Rc::new(script)).unwrap_err();
+        assert_eq!(err.message(), "Can't run <script> as a script.");
+    }
+
+    #[test]
+    fn test_garbage_is_collected() {
+        let mut vm = Vm::new(Vec::new());
+        let source = "class Node { init(next) { this.next = next; } }
+var kept = Node(nil);
+for (var i = 0; i < 100; i = i + 1) {
+  var a = Node(nil);
+  a.next = Node(a);
+  var s = \"garbage\" + \"string\";
+}";
+        vm.run_source(source, "unittest.lox").unwrap();
+        let before = vm.heap().len();
+        vm.collect_garbage();
+        assert!(vm.heap().len() < before / 10, "{} objects left", vm.heap().len());
+        vm.run_source("print kept.next; print \"garbage\" + \"string\";", "unittest.lox")
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(vm.writer().clone()).unwrap(),
+            "nil\ngarbagestring\n"
+        );
+    }
+
+    #[test]
+    fn test_closures_share_captured_variables() {
+        let source = "\
+var get; var set;
+{
+  var x = 1;
+  fun g() { return x; }
+  fun s(v) { x = v; }
+  get = g; set = s;
+}
+set(5);
+print get();";
+        assert_eq!(run(source), ("5\n".to_string(), Ok(())));
+    }
+
+    #[test]
+    fn test_runtime_error_has_stack_trace() {
+        let source = "fun inner() { return nope; }\nfun outer() {\n  return inner();\n}\nouter();";
+        let (_, result) = run(source);
+        let errors = result.unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "Undefined variable: nope\nin inner(), called at unittest.lox:3:16\nin outer(), called at unittest.lox:5:7"
+        );
+        assert_eq!(
+            errors[0].location(),
+            Some(&Location::new_span(
+                "unittest.lox".to_string(),
+                Span::new(21, 25, 1, 22)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_trace_shows_stack_before_each_instruction() {
+        let mut vm = Vm::new(Vec::new());
+        vm.set_trace(true);
+        vm.run_source("print 1 + 2;", "unittest.lox").unwrap();
+        let expected = "          [ <script> ]
+0000    1 Constant            0 '1'
+          [ <script> ][ 1 ]
+0003    | Constant            1 '2'
+          [ <script> ][ 1 ][ 2 ]
+0006    | Add
+          [ <script> ][ 3 ]
+0007    | Print
+3
+          [ <script> ]
+0008    | Nil
+          [ <script> ][ nil ]
+0009    | Return
+";
+        assert_eq!(String::from_utf8(vm.writer().clone()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_wrong_arity() {
+        let (_, result) = run("fun f(a) {}\nf(1, 2);");
+        assert_eq!(
+            result.unwrap_err()[0].message().lines().next(),
+            Some("Expected 1 arguments but got 2.")
+        );
+    }
+
+    #[rstest]
+    #[case("print nope;")]
+    #[case("var a = 1;\nprint a +\n  nil;")]
+    #[case("var f = 1; f(2);")]
+    #[case("print nil.field;")]
+    #[case("print -\"s\";")]
+    #[case("class A {}\nA().missing();")]
+    #[case("fun f(a) {}\n{ f(1, 2); }")]
+    fn test_runtime_errors_match_the_tree_walker(#[case] source: &str) {
+        let (_, result) = run(source);
+        let errors = result.unwrap_err();
+        let mut lox = Lox::with_io(Vec::new(), std::io::empty());
+        let expected = lox.run_source(source, "unittest.lox").unwrap_err();
+        assert_eq!(errors[0].location(), expected[0].location());
+        assert_eq!(errors[0].message(), expected[0].message());
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::bytecode::vm::Vm;
+use crate::core::errors::LoxError;
+use crate::gc::GcConfig;
+use crate::Lox;
+
+const EXPECT_OUTPUT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+
+/// Which implementation runs the test scripts. Both are held to the same expectations.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Backend {
+    /// The tree-walking interpreter.
+    #[default]
+    TreeWalker,
+    /// The bytecode compiler and virtual machine.
+    Vm,
+}
+
+/// What a test script says should happen when it runs, read from comments in the style of the
+/// Crafting Interpreters test suite:
+///
+/// - `// expect: value` expects a line of output.
+/// - `// expect runtime error: msg` expects the script to stop with that runtime error on the
+///   line the comment is on.
+/// - `// [line N] Error ...` expects a compile error on line N and `// Error ...` one on the line
+///   the comment is on.
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct Expectations {
+    pub output: Vec<String>,
+    pub compile_errors: Vec<String>,
+    pub runtime_error: Option<(usize, String)>,
+}
+
+impl Expectations {
+    pub fn parse(source: &str) -> Self {
+        let mut expectations = Expectations::default();
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            // The markers are looked for anywhere on the line, so a `//` earlier on it, such as
+            // one in a string, doesn't hide them.
+            if let Some(value) = after(line, EXPECT_OUTPUT) {
+                expectations.output.push(value.to_string());
+            } else if let Some(message) = after(line, EXPECT_RUNTIME_ERROR) {
+                expectations.runtime_error = Some((line_number, message.to_string()));
+            } else if let Some(error) = after(line, "// [line ") {
+                expectations.compile_errors.push(format!("[line {}", error));
+            } else if let Some(error) = after(line, "// Error") {
+                expectations
+                    .compile_errors
+                    .push(format!("[line {}] Error{}", line_number, error));
+            }
+        }
+        expectations
+    }
+}
+
+/// The rest of the line after the first occurrence of the marker.
+fn after<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    line.find(marker).map(|start| &line[start + marker.len()..])
+}
+
+/// The outcome of running one test script. It passed if there are no failures, each of which
+/// explains a way the script didn't do what it expected.
+#[derive(Debug)]
+pub struct TestResult {
+    pub path: PathBuf,
+    pub failures: Vec<String>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.passed() {
+            true => write!(f, "PASS {}", self.path.display()),
+            false => {
+                write!(f, "FAIL {}", self.path.display())?;
+                self.failures
+                    .iter()
+                    .try_for_each(|failure| write!(f, "\n  {}", failure))
+            }
+        }
+    }
+}
+
+/// Every `.lox` file under the directory, searching subdirectories too, in sorted order.
+pub fn discover(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(discover(&path)?);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+pub fn run_dir(dir: &Path, backend: Backend, gc: GcConfig) -> io::Result<Vec<TestResult>> {
+    discover(dir)?
+        .into_iter()
+        .map(|path| run_file(&path, backend, gc))
+        .collect()
+}
+
+pub fn run_file(path: &Path, backend: Backend, gc: GcConfig) -> io::Result<TestResult> {
+    let source = fs::read_to_string(path)?;
+    let failures = check_source(&source, &path.display().to_string(), backend, gc);
+    Ok(TestResult {
+        path: path.to_path_buf(),
+        failures,
+    })
+}
+
+/// Runs the source on the backend, with its heap tuned by `gc`, and compares what happened with
+/// what its comments expect.
+pub fn check_source(source: &str, filename: &str, backend: Backend, gc: GcConfig) -> Vec<String> {
+    let expected = Expectations::parse(source);
+    let (output, errors) = run_source(source, filename, backend, gc);
+    let output = String::from_utf8_lossy(&output).to_string();
+    let output: Vec<String> = output.lines().map(|line| line.to_string()).collect();
+
+    let (compile_errors, runtime_errors): (Vec<&LoxError>, Vec<&LoxError>) = errors
+        .iter()
+        .partition(|err| matches!(err, LoxError::SyntaxError(_, _)));
+    let compile_errors: Vec<String> = compile_errors
+        .into_iter()
+        .map(|err| compile_error_line(err, source))
+        .collect();
+
+    let mut failures = Vec::new();
+    failures.extend(diff("output", &expected.output, &output));
+    failures.extend(diff("compile errors", &expected.compile_errors, &compile_errors));
+    let runtime_error = runtime_errors.first().map(|err| {
+        let line = err.location().and_then(|location| location.line()).unwrap_or(0);
+        let message = err.message().lines().next().unwrap_or_default().to_string();
+        (line, message)
+    });
+    if runtime_error != expected.runtime_error {
+        let describe = |error: &Option<(usize, String)>| match error {
+            Some((line, message)) => format!("'{}' on line {}", message, line),
+            None => "none".to_string(),
+        };
+        failures.push(format!(
+            "runtime error: expected {} but got {}",
+            describe(&expected.runtime_error),
+            describe(&runtime_error)
+        ));
+    }
+    failures
+}
+
+/// Runs the source, returning what it printed and any errors.
+fn run_source(source: &str, filename: &str, backend: Backend, gc: GcConfig) -> (Vec<u8>, Vec<LoxError>) {
+    match backend {
+        Backend::TreeWalker => {
+            let mut lox = Lox::with_io(Vec::new(), io::empty());
+            lox.set_gc_config(gc);
+            let errors = lox.run_source(source, filename).err().unwrap_or_default();
+            (std::mem::take(lox.writer()), errors)
+        }
+        Backend::Vm => {
+            let mut vm = Vm::new(Vec::new());
+            vm.heap_mut().set_config(gc);
+            let errors = vm.run_source(source, filename).err().unwrap_or_default();
+            (std::mem::take(vm.writer()), errors)
+        }
+    }
+}
+
+/// Formats a compile error like the reference implementation, naming the source it points at.
+fn compile_error_line(error: &LoxError, source: &str) -> String {
+    let line = error.location().and_then(|location| location.line()).unwrap_or(0);
+    let message = error.message().lines().next().unwrap_or_default();
+    let span = error.location().and_then(|location| location.span());
+    let at = match span.and_then(|span| source.get(span.start..span.end)) {
+        Some("") => " at end".to_string(),
+        Some(lexeme) => format!(" at '{}'", lexeme),
+        None => String::new(),
+    };
+    format!("[line {}] Error{}: {}", line, at, message)
+}
+
+/// Lines showing how the actual lines differ from the expected ones, prefixed with `-` for
+/// expected and `+` for actual. Nothing is returned when they match.
+fn diff(label: &str, expected: &[String], actual: &[String]) -> Vec<String> {
+    if expected == actual {
+        return Vec::new();
+    }
+    let mut lines = vec![format!("unexpected {}:", label)];
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(expected), Some(actual)) if expected == actual => lines.push(format!("    {}", actual)),
+            (expected, actual) => {
+                lines.extend(expected.map(|line| format!("  - {}", line)));
+                lines.extend(actual.map(|line| format!("  + {}", line)));
+            }
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn test_parse_expectations() {
+        let source = "\
+print 1; // expect: 1
+var a = ; // Error at ';': Expected expression and found None.
+// [line 5] Error at end: Expect ; after value.
+print nope; // expect runtime error: Undefined variable: nope
+print 2";
+        assert_eq!(
+            Expectations::parse(source),
+            Expectations {
+                output: vec!["1".to_string()],
+                compile_errors: vec![
+                    "[line 2] Error at ';': Expected expression and found None.".to_string(),
+                    "[line 5] Error at end: Expect ; after value.".to_string()
+                ],
+                runtime_error: Some((4, "Undefined variable: nope".to_string())),
+            }
+        );
+    }
+
+    #[rstest]
+    #[case("print \"http://x\"; // expect: http://x", Backend::TreeWalker)]
+    #[case("print \"http://x\"; // expect: http://x", Backend::Vm)]
+    #[case(
+        "print \"a // b\" + nope; // expect runtime error: Undefined variable: nope",
+        Backend::TreeWalker
+    )]
+    fn test_slashes_before_the_marker_are_ignored(#[case] source: &str, #[case] backend: Backend) {
+        assert_eq!(
+            check_source(source, "unittest.lox", backend, GcConfig::default()),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_passing_script() {
+        let source = "print 1; // expect: 1\nprint nope; // expect runtime error: Undefined variable: nope";
+        assert_eq!(
+            check_source(source, "unittest.lox", Backend::TreeWalker, GcConfig::default()),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_passing_script_on_the_vm() {
+        let source = "print 1; // expect: 1\nprint nope; // expect runtime error: Undefined variable: nope";
+        assert_eq!(
+            check_source(source, "unittest.lox", Backend::Vm, GcConfig::default()),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_compile_errors_are_compared() {
+        let source = "print 1 +; // Error at ';': Expected expression and found None.\nprint 2";
+        assert_eq!(
+            check_source(source, "unittest.lox", Backend::TreeWalker, GcConfig::default()),
+            vec![
+                "unexpected compile errors:",
+                "    [line 1] Error at ';': Expected expression and found None.",
+                "  + [line 2] Error at end: Expect ; after value."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failures_show_a_diff() {
+        let source = "print 1; // expect: 1\nprint 3; // expect: 2\nprint nope;";
+        assert_eq!(
+            check_source(source, "unittest.lox", Backend::TreeWalker, GcConfig::default()),
+            vec![
+                "unexpected output:",
+                "    1",
+                "  - 2",
+                "  + 3",
+                "runtime error: expected none but got 'Undefined variable: nope' on line 3"
+            ]
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use std::fmt::Write;
+
+use super::errors::LoxError;
+use super::location::{Location, Span};
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// An error ready to be shown to a person, with the source it points at underlined.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    pub kind: String,
+    pub message: String,
+    pub location: Location,
+    pub label: Option<String>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(kind: &str, message: &str, location: Location) -> Self {
+        Diagnostic {
+            kind: kind.to_string(),
+            message: message.to_string(),
+            location,
+            label: None,
+            notes: Vec::new(),
+            help: None,
+        }
+    }
+
+    /// The first line of the error's message becomes the headline and any further lines are
+    /// kept as notes. Errors the interpreter knows how to describe in a few words get a label.
+    pub fn from_error(error: &LoxError) -> Self {
+        let kind = match error {
+            LoxError::SyntaxError(_, _) => "syntax error",
+            LoxError::RuntimeError(_, _) => "runtime error",
+            LoxError::Syscall(_, _) => "syscall error",
+            LoxError::LoadError(_, _) => "load error",
+            LoxError::Critical(_) => "critical error",
+        };
+        let location = error.location().cloned().unwrap_or(Location::Unknown);
+        let mut lines = error.message().lines();
+        let mut diagnostic = Diagnostic::new(kind, lines.next().unwrap_or_default(), location);
+        diagnostic.notes = lines.map(|line| line.to_string()).collect();
+        diagnostic.label = label(&diagnostic.message);
+        diagnostic
+    }
+
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.notes.push(note.to_string());
+        self
+    }
+
+    pub fn with_help(mut self, help: &str) -> Self {
+        self.help = Some(help.to_string());
+        self
+    }
+
+    /// Renders the diagnostic. When the source the location refers to is given, the lines it
+    /// covers are printed with the span underlined. `color` turns on ANSI colour codes.
+    pub fn render(&self, source: Option<&str>, color: bool) -> String {
+        let paint = |style: &str, text: &str| match color {
+            true => format!("{}{}{}", style, text, RESET),
+            false => text.to_string(),
+        };
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{}{}",
+            paint(RED, &self.kind),
+            paint(BOLD, &format!(": {}", self.message))
+        );
+
+        let snippet = match (source, self.snippet_span(source)) {
+            (Some(source), Some(span)) => SnippetLines::new(source, &span),
+            _ => None,
+        };
+        let gutter_width = match &snippet {
+            Some(lines) => lines.last_line_number().to_string().len(),
+            None => self.location.line().map_or(1, |line| line.to_string().len()),
+        };
+        let gutter = " ".repeat(gutter_width);
+
+        if let Some(position) = self.location.position() {
+            let _ = writeln!(out, "{}{} {}", gutter, paint(BLUE, "-->"), position);
+        }
+
+        if let Some(lines) = snippet {
+            let _ = writeln!(out, "{} {}", gutter, paint(BLUE, "|"));
+            let count = lines.lines.len();
+            for (i, line) in lines.lines.iter().enumerate() {
+                let number = format!("{:>width$}", line.number, width = gutter_width);
+                let _ = writeln!(out, "{} {} {}", paint(BLUE, &number), paint(BLUE, "|"), line.text);
+                let mut underline = format!(
+                    "{}{}",
+                    " ".repeat(line.underline_start),
+                    "^".repeat(line.underline_len)
+                );
+                if i + 1 == count {
+                    if let Some(label) = &self.label {
+                        underline = format!("{} {}", underline, label);
+                    }
+                }
+                let _ = writeln!(out, "{} {} {}", gutter, paint(BLUE, "|"), paint(RED, &underline));
+            }
+        }
+
+        for note in &self.notes {
+            let _ = writeln!(out, "{} {} note: {}", gutter, paint(BLUE, "="), note);
+        }
+        if let Some(help) = &self.help {
+            let _ = writeln!(out, "{} {} help: {}", gutter, paint(BLUE, "="), help);
+        }
+        out
+    }
+
+    /// The span to underline. A location that only knows its line underlines the whole line and
+    /// the end of file is a zero width span after the last character.
+    fn snippet_span(&self, source: Option<&str>) -> Option<Span> {
+        let source = source?;
+        match &self.location {
+            Location::Span(_, span) => Some(*span),
+            Location::Line(_, line) => {
+                let start = line_start_offset(source, *line)?;
+                let end = source[start..].find('\n').map_or(source.len(), |x| start + x);
+                Some(Span::new(start, end, *line, 1))
+            }
+            Location::Eof(_) => {
+                let end = source.trim_end_matches('\n').len();
+                let line = source[..end].matches('\n').count() + 1;
+                let line_start = source[..end].rfind('\n').map_or(0, |x| x + 1);
+                let column = source[line_start..end].chars().count() + 1;
+                Some(Span::new(end, end, line, column))
+            }
+            Location::Unknown | Location::Byte(_, _) => None,
+        }
+    }
+}
+
+struct SnippetLine {
+    number: usize,
+    text: String,
+    underline_start: usize,
+    underline_len: usize,
+}
+
+struct SnippetLines {
+    lines: Vec<SnippetLine>,
+}
+
+impl SnippetLines {
+    /// Splits the source covered by the span into lines and works out, in characters, which part
+    /// of each line to underline. Returns `None` if the span doesn't fit the source.
+    fn new(source: &str, span: &Span) -> Option<Self> {
+        let first_line_start = line_start_offset(source, span.line)?;
+        if span.start < first_line_start || span.end < span.start || span.end > source.len() {
+            return None;
+        }
+        source.get(first_line_start..span.end)?;
+
+        let mut lines: Vec<SnippetLine> = Vec::new();
+        let mut line_start = first_line_start;
+        let mut number = span.line;
+        loop {
+            let line_end = source[line_start..]
+                .find('\n')
+                .map_or(source.len(), |x| line_start + x);
+            let text = &source[line_start..line_end];
+            let underline_from = span.start.max(line_start);
+            let underline_to = span.end.min(line_end);
+            let underline_start = source[line_start..underline_from].chars().count();
+            let underline_len = source[underline_from..underline_to].chars().count().max(1);
+            lines.push(SnippetLine {
+                number,
+                text: text.replace('\t', " "),
+                underline_start,
+                underline_len,
+            });
+            if span.end <= line_end + 1 || line_end == source.len() {
+                break;
+            }
+            line_start = line_end + 1;
+            number += 1;
+        }
+        Some(SnippetLines { lines })
+    }
+
+    fn last_line_number(&self) -> usize {
+        self.lines.last().map_or(1, |line| line.number)
+    }
+}
+
+/// Short labels for messages that start with these words.
+const LABELS: &[(&str, &str)] = &[
+    ("Expected expression", "expected expression"),
+    ("Undefined variable", "undefined variable"),
+    ("Undefined property", "undefined property"),
+    ("Unexpected character", "unexpected character"),
+    ("Unterminated string", "string starts here"),
+    ("Unterminated block comment", "comment starts here"),
+    ("Invalid number", "invalid number"),
+    ("Invalid escape sequence", "invalid escape"),
+    ("Invalid unicode escape", "invalid escape"),
+    ("Invalid assignment target", "can't assign to this"),
+    ("Expected two numbers", "operands must be numbers"),
+    ("Expected Value::Number", "operand must be a number"),
+    ("Can only call", "not callable"),
+    ("Only instances", "not an instance"),
+    ("Superclass must be a class", "not a class"),
+    ("Stack overflow", "too many nested calls"),
+    ("Already a variable", "already declared"),
+    ("Can't read local variable", "read in its own initializer"),
+    ("Can't", "not allowed here"),
+];
+
+/// A few words describing the error, to print next to the underline. Parser messages of the form
+/// "Expect X after Y." are labelled "expected X".
+fn label(message: &str) -> Option<String> {
+    if message.starts_with("Expected ") && message.contains(" arguments but got ") {
+        return Some("wrong number of arguments".to_string());
+    }
+    if let Some(rest) = message.strip_prefix("Expect ") {
+        let expected = [" after ", " before "]
+            .iter()
+            .filter_map(|word| rest.find(word))
+            .min()
+            .map_or(rest.trim_end_matches('.'), |end| &rest[..end]);
+        return Some(format!("expected {}", expected));
+    }
+    LABELS
+        .iter()
+        .find(|(prefix, _)| message.starts_with(prefix))
+        .map(|(_, label)| label.to_string())
+}
+
+/// Byte offset of the start of the 1-based line, if the source has that many lines.
+fn line_start_offset(source: &str, line: usize) -> Option<usize> {
+    if line == 0 {
+        return None;
+    }
+    let mut offset = 0;
+    for _ in 1..line {
+        offset += source[offset..].find('\n')? + 1;
+    }
+    Some(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn span(line: usize, column: usize, start: usize, end: usize) -> Location {
+        Location::new_span("unittest.lox".to_string(), Span::new(start, end, line, column))
+    }
+
+    #[test]
+    fn test_render_underlines_span() {
+        let source = "var a = 1;\nprint a + nope;\n";
+        let error = LoxError::RuntimeError(span(2, 11, 21, 25), "Undefined variable: nope".to_string());
+        let rendered = Diagnostic::from_error(&error)
+            .with_label("not defined")
+            .with_help("declare it with 'var' first")
+            .render(Some(source), false);
+        let expected = "\
+runtime error: Undefined variable: nope
+ --> unittest.lox:2:11
+  |
+2 | print a + nope;
+  |           ^^^^ not defined
+  = help: declare it with 'var' first
+";
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_render_multi_line_span_and_notes() {
+        let source = "print (1 +\n  2;";
+        let error = LoxError::SyntaxError(
+            span(1, 7, 6, 14),
+            "Expect ')' after expression.\nUnexpected token is SemiColon".to_string(),
+        );
+        let rendered = Diagnostic::from_error(&error).render(Some(source), false);
+        let expected = "\
+syntax error: Expect ')' after expression.
+ --> unittest.lox:1:7
+  |
+1 | print (1 +
+  |       ^^^^
+2 |   2;
+  | ^^^ expected ')'
+  = note: Unexpected token is SemiColon
+";
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_render_zero_width_span_at_end_of_file() {
+        let source = "print 1";
+        let error = LoxError::SyntaxError(span(1, 8, 7, 7), "Expect ';' after value.".to_string());
+        let rendered = Diagnostic::from_error(&error).render(Some(source), false);
+        assert!(
+            rendered.ends_with("1 | print 1\n  |        ^ expected ';'\n"),
+            "{}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn test_render_without_source_or_location() {
+        let error = LoxError::Critical("No tokens parsed.".to_string());
+        assert_eq!(
+            Diagnostic::from_error(&error).render(None, false),
+            "critical error: No tokens parsed.\n"
+        );
+
+        let error = LoxError::RuntimeError(span(9, 1, 100, 101), "Out of range.".to_string());
+        assert_eq!(
+            Diagnostic::from_error(&error).render(Some("short"), false),
+            "runtime error: Out of range.\n --> unittest.lox:9:1\n"
+        );
+    }
+
+    #[test]
+    fn test_render_with_color() {
+        let error = LoxError::Critical("Boom.".to_string());
+        assert_eq!(
+            Diagnostic::from_error(&error).render(None, true),
+            "\x1b[1;31mcritical error\x1b[0m\x1b[1m: Boom.\x1b[0m\n"
+        );
+    }
+}
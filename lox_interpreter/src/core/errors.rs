@@ -6,14 +6,98 @@ pub enum LoxError {
     SyntaxError(Location, String),
     RuntimeError(Location, String),
     Syscall(Location, String),
+    /// A compiled program that can't be loaded because it is damaged or from another version.
+    LoadError(Location, String),
     Critical(String),
 }
 
+/// How many lines a stack trace can take up before the frames past them are only counted.
+pub const MAX_TRACE_LINES: usize = 20;
+
 impl LoxError {
     pub fn new_syscall(filename: &str, line: usize, syscall_error: String) -> Self {
         let location = Location::Line(filename.to_string(), line);
         LoxError::Syscall(location, syscall_error)
     }
+
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            LoxError::SyntaxError(location, _)
+            | LoxError::RuntimeError(location, _)
+            | LoxError::Syscall(location, _)
+            | LoxError::LoadError(location, _) => Some(location),
+            LoxError::Critical(_) => None,
+        }
+    }
+
+    /// Adds a line to the end of the message. Diagnostics show these extra lines as notes.
+    pub fn with_note(self, note: &str) -> Self {
+        self.map_message(|msg| format!("{}\n{}", msg, note))
+    }
+
+    /// Adds a frame to the stack trace at the end of the message. A frame that is the same as
+    /// the one before it, as happens all the way down a deep recursion, is counted rather than
+    /// repeated, and once the trace is `MAX_TRACE_LINES` long further frames are only counted.
+    pub fn with_frame(self, frame: &str) -> Self {
+        self.map_message(|msg| {
+            let notes: Vec<&str> = msg.lines().skip(1).collect();
+            let (head, last) = match msg.rsplit_once('\n') {
+                Some((head, last)) if !notes.is_empty() => (head, last),
+                _ => return format!("{}\n{}", msg, frame),
+            };
+            if let Some(count) = counted(last, "... and ", " more") {
+                return format!("{}\n{}", head, more_frames(count + 1));
+            }
+            let previous = notes.len().checked_sub(2).map(|i| notes[i]);
+            match counted(last, "... repeated ", " more time") {
+                Some(count) if previous == Some(frame) => format!("{}\n{}", head, repeated(count + 1)),
+                _ if notes.len() >= MAX_TRACE_LINES => format!("{}\n{}", msg, more_frames(1)),
+                _ if last == frame => format!("{}\n{}", msg, repeated(1)),
+                _ => format!("{}\n{}", msg, frame),
+            }
+        })
+    }
+
+    fn map_message(self, f: impl FnOnce(&str) -> String) -> Self {
+        match self {
+            LoxError::SyntaxError(location, msg) => LoxError::SyntaxError(location, f(&msg)),
+            LoxError::RuntimeError(location, msg) => LoxError::RuntimeError(location, f(&msg)),
+            LoxError::Syscall(location, msg) => LoxError::Syscall(location, f(&msg)),
+            LoxError::LoadError(location, msg) => LoxError::LoadError(location, f(&msg)),
+            LoxError::Critical(msg) => LoxError::Critical(f(&msg)),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            LoxError::SyntaxError(_, msg)
+            | LoxError::RuntimeError(_, msg)
+            | LoxError::Syscall(_, msg)
+            | LoxError::LoadError(_, msg)
+            | LoxError::Critical(msg) => msg,
+        }
+    }
+}
+
+/// The count in a line made by `repeated` or `more_frames`.
+fn counted(line: &str, prefix: &str, suffix: &str) -> Option<usize> {
+    let rest = line.strip_prefix(prefix)?;
+    let end = rest.find(suffix)?;
+    rest[..end].parse().ok()
+}
+
+fn repeated(count: usize) -> String {
+    match count {
+        1 => "... repeated 1 more time".to_string(),
+        _ => format!("... repeated {} more times", count),
+    }
+}
+
+fn more_frames(count: usize) -> String {
+    match count {
+        1 => "... and 1 more frame".to_string(),
+        _ => format!("... and {} more frames", count),
+    }
 }
 
 impl fmt::Display for LoxError {
@@ -28,7 +112,54 @@ impl fmt::Display for LoxError {
             LoxError::Syscall(location, msg) => {
                 write!(f, "SysCall Error\n{}\nLocation @ {}", msg, location)
             }
+            LoxError::LoadError(location, msg) => {
+                write!(f, "Load Error\n{}\nLocation @ {}", msg, location)
+            }
             LoxError::Critical(msg) => write!(f, "\nCritical Error\n{}\nNo location can be determined.", msg),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(frames: &[&str]) -> String {
+        let err = LoxError::RuntimeError(Location::Unknown, "Oops.".to_string());
+        frames
+            .iter()
+            .fold(err, |err, frame| err.with_frame(frame))
+            .message()
+            .to_string()
+    }
+
+    #[test]
+    fn test_repeated_frames_are_counted() {
+        let frames = ["in f(), called at a.lox:1:2"; 1000];
+        assert_eq!(
+            trace(&frames),
+            "Oops.\nin f(), called at a.lox:1:2\n... repeated 999 more times"
+        );
+        assert_eq!(
+            trace(&[
+                "in f(), called at a.lox:1:2",
+                "in f(), called at a.lox:1:2",
+                "in g(), called at a.lox:3:1"
+            ]),
+            "Oops.\nin f(), called at a.lox:1:2\n... repeated 1 more time\nin g(), called at a.lox:3:1"
+        );
+    }
+
+    #[test]
+    fn test_long_traces_are_cut_short() {
+        let frames: Vec<String> = (0..100)
+            .map(|i| format!("in {}(), called at a.lox:{}:1", ["f", "g"][i % 2], i))
+            .collect();
+        let frames: Vec<&str> = frames.iter().map(|frame| frame.as_str()).collect();
+        let message = trace(&frames);
+        let lines: Vec<&str> = message.lines().collect();
+        assert_eq!(lines.len(), 1 + MAX_TRACE_LINES + 1);
+        assert_eq!(lines[MAX_TRACE_LINES], "in g(), called at a.lox:19:1");
+        assert_eq!(lines[MAX_TRACE_LINES + 1], "... and 80 more frames");
+    }
+}
@@ -1,10 +1,49 @@
 use std::fmt;
 
+/// A range of the source code. `start` and `end` are byte offsets into the source, `end` being
+/// exclusive, while `line` and `column` are 1-based and give where the range starts. Columns
+/// count characters rather than bytes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Self {
+        Span {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    /// The smallest span that covers both spans.
+    pub fn merge(&self, other: &Span) -> Span {
+        let first = match self.start <= other.start {
+            true => self,
+            false => other,
+        };
+        Span {
+            start: first.start,
+            end: self.end.max(other.end),
+            line: first.line,
+            column: first.column,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Location {
     Unknown,
     Eof(String),
     Line(String, usize),
+    Span(String, Span),
+    /// A byte offset into a file that isn't source code, such as a compiled program.
+    Byte(String, usize),
 }
 
 impl Location {
@@ -15,6 +54,62 @@ impl Location {
     pub fn new_eof(filename: String) -> Self {
         Location::Eof(filename)
     }
+
+    pub fn new_span(filename: String, span: Span) -> Self {
+        Location::Span(filename, span)
+    }
+
+    pub fn new_byte(filename: String, offset: usize) -> Self {
+        Location::Byte(filename, offset)
+    }
+
+    pub fn filename(&self) -> Option<&str> {
+        match self {
+            Location::Unknown => None,
+            Location::Eof(filename)
+            | Location::Line(filename, _)
+            | Location::Span(filename, _)
+            | Location::Byte(filename, _) => Some(filename),
+        }
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Location::Line(_, line) => Some(*line),
+            Location::Span(_, span) => Some(span.line),
+            Location::Unknown | Location::Eof(_) | Location::Byte(_, _) => None,
+        }
+    }
+
+    pub fn span(&self) -> Option<&Span> {
+        match self {
+            Location::Span(_, span) => Some(span),
+            _ => None,
+        }
+    }
+
+    /// Where this location is as `file:line:column`, or as much of that as is known.
+    pub fn position(&self) -> Option<String> {
+        match self {
+            Location::Unknown => None,
+            Location::Eof(filename) => Some(format!("{} at end of file", filename)),
+            Location::Line(filename, line) => Some(format!("{}:{}", filename, line)),
+            Location::Span(filename, span) => Some(format!("{}:{}:{}", filename, span.line, span.column)),
+            Location::Byte(filename, offset) => Some(format!("{} at byte {}", filename, offset)),
+        }
+    }
+
+    /// A location covering this one through to `end`. When either side has no span there is
+    /// nothing to merge so this location is returned unchanged.
+    pub fn to(&self, end: &Location) -> Location {
+        match (self, end) {
+            (Location::Span(filename, start), Location::Span(_, end)) => {
+                Location::Span(filename.clone(), start.merge(end))
+            }
+            (Location::Unknown, _) => end.clone(),
+            _ => self.clone(),
+        }
+    }
 }
 
 impl fmt::Display for Location {
@@ -29,6 +124,42 @@ impl fmt::Display for Location {
                 let message = format!("Error at -> {filename}:{line}", filename = filename, line = line);
                 f.write_str(&message)
             }
+            Location::Span(filename, span) => {
+                let message = format!(
+                    "Error at -> {filename}:{line}:{column}",
+                    filename = filename,
+                    line = span.line,
+                    column = span.column
+                );
+                f.write_str(&message)
+            }
+            Location::Byte(filename, offset) => {
+                write!(f, "Error at -> {} byte {}", filename, offset)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_merges_spans() {
+        let start = Location::new_span("unittest.lox".to_string(), Span::new(4, 7, 1, 5));
+        let end = Location::new_span("unittest.lox".to_string(), Span::new(12, 13, 2, 3));
+        assert_eq!(
+            start.to(&end),
+            Location::new_span("unittest.lox".to_string(), Span::new(4, 13, 1, 5))
+        );
+        assert_eq!(end.to(&start), start.to(&end));
+    }
+
+    #[test]
+    fn test_to_without_spans_keeps_location() {
+        let line = Location::new_line("unittest.lox".to_string(), 3);
+        let eof = Location::new_eof("unittest.lox".to_string());
+        assert_eq!(line.to(&eof), line);
+        assert_eq!(Location::Unknown.to(&eof), eof);
+    }
+}
@@ -1,2 +1,4 @@
+pub mod diagnostics;
 pub mod errors;
 pub mod location;
+pub mod sources;
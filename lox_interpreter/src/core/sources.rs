@@ -0,0 +1,113 @@
+use std::collections::{HashMap, VecDeque};
+
+/// How many sources a `Sources` keeps unless told otherwise.
+pub const DEFAULT_SOURCE_CAPACITY: usize = 100;
+
+/// The source code that was run, by the name it was given, so errors can be shown with the code
+/// they point at. Errors can point at code from earlier runs, such as a function defined by one
+/// line of a session and called by another, so sources are kept after they finish running. Only
+/// the most recent are kept, and once `capacity` is reached the oldest is forgotten. Errors
+/// pointing at a forgotten source are shown without the code.
+#[derive(Debug, Clone)]
+pub struct Sources {
+    sources: HashMap<String, String>,
+    // Names from oldest to newest.
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl Sources {
+    /// Keeps up to `capacity` sources. A capacity of 0 keeps none.
+    pub fn new(capacity: usize) -> Self {
+        Sources {
+            sources: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Remembers the source, replacing any earlier one with the same name.
+    pub fn insert(&mut self, name: &str, source: &str) {
+        if self.sources.remove(name).is_some() {
+            self.order.retain(|other| other != name);
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        while self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.sources.remove(&oldest);
+            }
+        }
+        self.sources.insert(name.to_string(), source.to_string());
+        self.order.push_back(name.to_string());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.sources.get(name).map(|source| source.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Changes how many sources are kept, forgetting the oldest if there are now too many.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.order.len() > capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.sources.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl Default for Sources {
+    fn default() -> Self {
+        Sources::new(DEFAULT_SOURCE_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_source_is_forgotten() {
+        let mut sources = Sources::new(2);
+        sources.insert("a", "print 1;");
+        sources.insert("b", "print 2;");
+        sources.insert("c", "print 3;");
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources.get("a"), None);
+        assert_eq!(sources.get("b"), Some("print 2;"));
+        assert_eq!(sources.get("c"), Some("print 3;"));
+    }
+
+    #[test]
+    fn test_running_a_name_again_makes_it_the_newest() {
+        let mut sources = Sources::new(2);
+        sources.insert("a", "print 1;");
+        sources.insert("b", "print 2;");
+        sources.insert("a", "print 3;");
+        sources.insert("c", "print 4;");
+        assert_eq!(sources.get("a"), Some("print 3;"));
+        assert_eq!(sources.get("b"), None);
+        assert_eq!(sources.len(), 2);
+    }
+
+    #[test]
+    fn test_zero_capacity_keeps_nothing() {
+        let mut sources = Sources::new(1);
+        sources.insert("a", "print 1;");
+        sources.set_capacity(0);
+        assert!(sources.is_empty());
+        sources.insert("b", "print 2;");
+        assert_eq!(sources.get("b"), None);
+        assert!(sources.is_empty());
+    }
+}
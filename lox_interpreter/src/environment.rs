@@ -1,13 +1,80 @@
+use std::cell::RefCell;
 use std::collections::{hash_map::Entry, HashMap};
+use std::rc::Rc;
 
 use crate::{
     core::{errors::LoxError, location::Location},
+    heap::Heap,
+    snapshot::HeapSnapshot,
     value::Value,
 };
 
+/// A shared handle to a scope. Function values hold one of these to keep their defining scope
+/// alive after the block that created it has finished.
+pub type ScopeRef = Rc<RefCell<Scope>>;
+
+#[derive(Default)]
+pub struct Scope {
+    values: HashMap<String, Value>,
+    enclosing: Option<ScopeRef>,
+}
+
+impl Scope {
+    /// A scope on its own. Scopes are allocated on the heap with `Heap::alloc_scope`.
+    pub fn new(enclosing: Option<ScopeRef>) -> Self {
+        Scope {
+            values: HashMap::new(),
+            enclosing,
+        }
+    }
+
+    pub fn enclosing(&self) -> Option<&ScopeRef> {
+        self.enclosing.as_ref()
+    }
+
+    pub fn bindings(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.values.iter()
+    }
+
+    /// Removes every binding and the link to the enclosing scope, for the garbage collector to
+    /// break cycles through this scope.
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.enclosing = None;
+    }
+
+    pub fn define(&mut self, key: String, value: Value) {
+        self.values.insert(key, value);
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        match self.values.get(key) {
+            Some(x) => Some(x.clone()),
+            None => match &self.enclosing {
+                Some(enclosing) => enclosing.borrow().get(key),
+                None => None,
+            },
+        }
+    }
+
+    fn assign(&mut self, key: &str, value: Value) -> bool {
+        if let Entry::Occupied(mut e) = self.values.entry(key.to_string()) {
+            e.insert(value);
+            return true;
+        }
+        match &self.enclosing {
+            Some(enclosing) => enclosing.borrow_mut().assign(key, value),
+            None => false,
+        }
+    }
+}
+
 pub struct Environment {
-    scopes: Vec<HashMap<String, Value>>,
-    index: usize,
+    globals: ScopeRef,
+    current: ScopeRef,
+    // Dropped after the scopes above, so that by then only the heap and other objects on it
+    // refer to them and the heap's final collection frees them.
+    heap: Heap,
 }
 
 impl Default for Environment {
@@ -18,54 +85,147 @@ impl Default for Environment {
 
 impl Environment {
     pub fn new() -> Self {
-        let scopes_list: Vec<HashMap<String, Value>> = vec![HashMap::new()];
+        let mut heap = Heap::new();
+        let globals = heap.alloc_scope(None);
         Environment {
-            scopes: scopes_list,
-            index: 0,
+            current: Rc::clone(&globals),
+            globals,
+            heap,
         }
     }
 
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// A snapshot of the heap, with the global and current scopes as its named roots.
+    pub fn snapshot(&self) -> HeapSnapshot {
+        self.heap
+            .snapshot(&[("globals", &self.globals), ("current scope", &self.current)])
+    }
+
     pub fn new_child_scope(&mut self) {
-        self.scopes.push(HashMap::new());
-        self.index += 1;
+        self.current = self.heap.alloc_scope(Some(&self.current));
     }
 
     pub fn destroy_child_scope(&mut self) {
-        match self.scopes.pop() {
-            Some(_) => (),
-            _ => panic!("Internal error popping the child scope."),
-        }
-        self.index -= 1;
+        let enclosing = match &self.current.borrow().enclosing {
+            Some(x) => Rc::clone(x),
+            None => panic!("Internal error popping the child scope."),
+        };
+        self.current = enclosing;
+    }
+
+    /// Returns a handle to the current scope so it can be captured by a closure.
+    pub fn capture(&self) -> ScopeRef {
+        Rc::clone(&self.current)
+    }
+
+    /// Makes a new child of `closure` the current scope and returns the scope that was current
+    /// before, which must be handed back to `restore_scope` once the closure has finished running.
+    pub fn enter_closure(&mut self, closure: &ScopeRef) -> ScopeRef {
+        let scope = self.heap.alloc_scope(Some(closure));
+        std::mem::replace(&mut self.current, scope)
+    }
+
+    pub fn restore_scope(&mut self, previous: ScopeRef) {
+        self.current = previous;
     }
 
     pub fn define(&mut self, key: String, value: Value) {
-        let current_scope = &mut self.scopes[self.index];
-        current_scope.insert(key, value);
+        self.current.borrow_mut().define(key, value);
     }
 
     pub fn get(&self, key: &str) -> Option<Value> {
-        for i in (0..=self.index).rev() {
-            let current_scope = &self.scopes[i];
-            let value = current_scope.get(key);
-            if let Some(x) = value {
-                return Some(x.clone());
+        self.current.borrow().get(key)
+    }
+
+    pub fn define_global(&mut self, key: String, value: Value) {
+        self.globals.borrow_mut().define(key, value);
+    }
+
+    /// Returns every global binding sorted by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let mut bindings: Vec<(String, Value)> = self
+            .globals
+            .borrow()
+            .values
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        bindings.sort_by(|a, b| a.0.cmp(&b.0));
+        bindings
+    }
+
+    /// Gets a variable from the scope `depth` levels out from the current one, as computed by the
+    /// resolver, without searching any other scope.
+    pub fn get_at(&self, depth: usize, key: &str) -> Option<Value> {
+        self.ancestor(depth).borrow().values.get(key).cloned()
+    }
+
+    pub fn get_global(&self, key: &str) -> Option<Value> {
+        self.globals.borrow().values.get(key).cloned()
+    }
+
+    pub fn assign_at(
+        &mut self,
+        depth: usize,
+        key: &str,
+        value: Value,
+        location: Location,
+    ) -> Result<Value, LoxError> {
+        let scope = self.ancestor(depth);
+        let mut scope = scope.borrow_mut();
+        Environment::assign_in_scope(&mut scope, key, value, location)
+    }
+
+    pub fn assign_global(&mut self, key: &str, value: Value, location: Location) -> Result<Value, LoxError> {
+        let mut globals = self.globals.borrow_mut();
+        Environment::assign_in_scope(&mut globals, key, value, location)
+    }
+
+    fn assign_in_scope(
+        scope: &mut Scope,
+        key: &str,
+        value: Value,
+        location: Location,
+    ) -> Result<Value, LoxError> {
+        match scope.values.get_mut(key) {
+            Some(x) => {
+                *x = value.clone();
+                Ok(value)
             }
+            None => Err(LoxError::RuntimeError(
+                location,
+                format!("Undefined variable: {}", key),
+            )),
+        }
+    }
+
+    fn ancestor(&self, depth: usize) -> ScopeRef {
+        let mut scope = Rc::clone(&self.current);
+        for _ in 0..depth {
+            let enclosing = match &scope.borrow().enclosing {
+                Some(x) => Rc::clone(x),
+                None => panic!("Internal error resolving a scope {} levels out.", depth),
+            };
+            scope = enclosing;
         }
-        None
+        scope
     }
 
     pub fn assign(&mut self, key: &str, value: Value, location: Location) -> Result<Value, LoxError> {
-        for i in (0..=self.index).rev() {
-            let current_scope = &mut self.scopes[i];
-            if let Entry::Occupied(mut e) = current_scope.entry(key.to_string()) {
-                e.insert(value.clone());
-                return Ok(value);
-            }
+        match self.current.borrow_mut().assign(key, value.clone()) {
+            true => Ok(value),
+            false => Err(LoxError::RuntimeError(
+                location,
+                format!("Undefined variable: {}", key),
+            )),
         }
-        Err(LoxError::RuntimeError(
-            location,
-            format!("Undefined variable: {}", key),
-        ))
     }
 }
 
@@ -80,9 +240,8 @@ mod tests {
     #[case("baz", Some(Value::Nil))]
     fn test_set_and_get_variable(#[case] name: &str, #[case] value: Option<Value>) {
         let mut env = Environment::new();
-        match &value {
-            Some(x) => env.define(name.to_string(), x.clone()),
-            None => {}
+        if let Some(x) = &value {
+            env.define(name.to_string(), x.clone());
         }
         assert_eq!(value, env.get(name));
     }
@@ -128,4 +287,37 @@ mod tests {
         assert_eq!(result, Ok(Value::Number(45.0)));
         assert_eq!(env.get("foo"), Some(Value::Number(45.0)));
     }
+
+    #[test]
+    fn test_captured_scope_outlives_child_scope() {
+        let mut env = Environment::new();
+        env.new_child_scope();
+        env.define("foo".to_string(), Value::Number(42.0));
+        let captured = env.capture();
+        env.destroy_child_scope();
+        assert_eq!(None, env.get("foo"));
+
+        let previous = env.enter_closure(&captured);
+        assert_eq!(Some(Value::Number(42.0)), env.get("foo"));
+        env.restore_scope(previous);
+        assert_eq!(None, env.get("foo"));
+    }
+
+    #[test]
+    fn test_get_and_assign_at_depth() {
+        let mut env = Environment::new();
+        env.define("foo".to_string(), Value::Number(1.0));
+        env.new_child_scope();
+        env.define("foo".to_string(), Value::Number(2.0));
+        env.new_child_scope();
+        assert_eq!(env.get_at(1, "foo"), Some(Value::Number(2.0)));
+        assert_eq!(env.get_at(2, "foo"), Some(Value::Number(1.0)));
+        assert_eq!(env.get_at(0, "foo"), None);
+
+        let location = Location::Line("testfile.lox".to_string(), 85);
+        let result = env.assign_at(2, "foo", Value::Number(3.0), location.clone());
+        assert_eq!(result, Ok(Value::Number(3.0)));
+        assert_eq!(env.get_global("foo"), Some(Value::Number(3.0)));
+        assert!(env.assign_at(0, "foo", Value::Nil, location).is_err());
+    }
 }
@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+/// How many bytes a heap can allocate before its first collection.
+pub const DEFAULT_THRESHOLD: usize = 1024 * 1024;
+
+/// How much a heap can grow after a collection before the next one, relative to what survived.
+pub const DEFAULT_GROWTH_FACTOR: f64 = 2.0;
+
+/// When a heap collects its garbage. Both backends' heaps are tuned with this.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcConfig {
+    /// The bytes allocated that trigger the first collection. Later thresholds never go below it.
+    pub threshold: usize,
+    /// After a collection, the next one is triggered once the heap reaches this many times the
+    /// bytes that survived.
+    pub growth_factor: f64,
+    /// Collects before every allocation, so an object that is in use but not reachable from the
+    /// roots is freed straight away instead of once in a while.
+    pub stress: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            threshold: DEFAULT_THRESHOLD,
+            growth_factor: DEFAULT_GROWTH_FACTOR,
+            stress: false,
+        }
+    }
+}
+
+impl GcConfig {
+    /// Collects on every allocation, with the default thresholds otherwise.
+    pub fn stress() -> Self {
+        GcConfig {
+            stress: true,
+            ..GcConfig::default()
+        }
+    }
+}
+
+/// What a heap has allocated and collected over its lifetime, for keeping an eye on the memory
+/// use of a long running interpreter. Sizes are estimates of what objects take up, not counts
+/// of what the allocator hands out.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GcStats {
+    /// Objects on the heap now, including garbage that hasn't been collected yet.
+    pub objects: usize,
+    /// Bytes taken up by the objects on the heap now.
+    pub bytes_in_use: usize,
+    /// Bytes taken up by the objects that survived the last collection.
+    pub bytes_live: usize,
+    pub allocations: u64,
+    pub bytes_allocated: u64,
+    pub collections: u64,
+    pub objects_freed: u64,
+    /// How long the program was stopped for while collections ran, in total and at most.
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+    pub last_pause: Duration,
+}
+
+/// Keeps count of what a heap has allocated and collected and decides when it is time to
+/// collect.
+#[derive(Debug, Clone)]
+pub struct GcBudget {
+    config: GcConfig,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stats: GcStats,
+}
+
+impl Default for GcBudget {
+    fn default() -> Self {
+        GcBudget::new(GcConfig::default())
+    }
+}
+
+impl GcBudget {
+    pub fn new(config: GcConfig) -> Self {
+        GcBudget {
+            config,
+            bytes_allocated: 0,
+            next_gc: config.threshold,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> GcConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.config = config;
+        self.next_gc = self.next_threshold(self.bytes_allocated);
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.config.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn allocated(&mut self, bytes: usize) {
+        self.bytes_allocated += bytes;
+        self.stats.allocations += 1;
+        self.stats.bytes_allocated += bytes as u64;
+    }
+
+    /// Records that a collection freed `freed` objects in `pause`, leaving `live_bytes`
+    /// allocated, and sets the next threshold.
+    pub fn collected(&mut self, live_bytes: usize, freed: usize, pause: Duration) {
+        self.bytes_allocated = live_bytes;
+        self.next_gc = self.next_threshold(live_bytes);
+        self.stats.bytes_live = live_bytes;
+        self.stats.collections += 1;
+        self.stats.objects_freed += freed as u64;
+        self.stats.total_pause += pause;
+        self.stats.max_pause = self.stats.max_pause.max(pause);
+        self.stats.last_pause = pause;
+    }
+
+    /// The statistics so far, for a heap now holding `objects` objects.
+    pub fn stats(&self, objects: usize) -> GcStats {
+        GcStats {
+            objects,
+            bytes_in_use: self.bytes_allocated,
+            ..self.stats
+        }
+    }
+
+    fn next_threshold(&self, live_bytes: usize) -> usize {
+        let grown = (live_bytes as f64 * self.config.growth_factor) as usize;
+        grown.max(self.config.threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collects_once_over_the_threshold() {
+        let mut budget = GcBudget::new(GcConfig {
+            threshold: 100,
+            growth_factor: 2.0,
+            stress: false,
+        });
+        budget.allocated(100);
+        assert!(!budget.should_collect());
+        budget.allocated(1);
+        assert!(budget.should_collect());
+
+        // 80 bytes survive, so the heap can grow to 160 before the next collection.
+        budget.collected(80, 3, Duration::ZERO);
+        budget.allocated(80);
+        assert!(!budget.should_collect());
+        budget.allocated(1);
+        assert!(budget.should_collect());
+    }
+
+    #[test]
+    fn test_threshold_never_drops_below_the_initial_one() {
+        let mut budget = GcBudget::new(GcConfig {
+            threshold: 100,
+            growth_factor: 2.0,
+            stress: false,
+        });
+        budget.collected(10, 0, Duration::ZERO);
+        budget.allocated(90);
+        assert!(!budget.should_collect());
+    }
+
+    #[test]
+    fn test_stats_add_up_allocations_and_collections() {
+        let mut budget = GcBudget::default();
+        budget.allocated(100);
+        budget.allocated(50);
+        budget.collected(40, 1, Duration::from_millis(2));
+        budget.allocated(10);
+        budget.collected(30, 1, Duration::from_millis(1));
+        assert_eq!(
+            budget.stats(2),
+            GcStats {
+                objects: 2,
+                bytes_in_use: 30,
+                bytes_live: 30,
+                allocations: 3,
+                bytes_allocated: 160,
+                collections: 2,
+                objects_freed: 2,
+                total_pause: Duration::from_millis(3),
+                max_pause: Duration::from_millis(2),
+                last_pause: Duration::from_millis(1),
+            }
+        );
+    }
+
+    #[test]
+    fn test_stress_collects_every_time() {
+        let budget = GcBudget::new(GcConfig::stress());
+        assert!(budget.should_collect());
+    }
+}
@@ -0,0 +1,563 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of;
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::environment::{Scope, ScopeRef};
+use crate::gc::{GcBudget, GcConfig, GcStats};
+use crate::snapshot::{HeapSnapshot, SnapshotObject, SnapshotReference, SnapshotRoot};
+use crate::value::{LoxClass, LoxFunction, LoxInstance, Value};
+
+// The reference counts an `Rc` keeps next to its value.
+const RC_HEADER: usize = 2 * size_of::<usize>();
+
+/// Something the tree-walking interpreter allocated that can be part of a reference cycle.
+enum HeapObject {
+    Scope(ScopeRef),
+    Function(Rc<LoxFunction>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<LoxInstance>),
+}
+
+impl HeapObject {
+    fn address(&self) -> usize {
+        match self {
+            HeapObject::Scope(scope) => address(scope),
+            HeapObject::Function(function) => address(function),
+            HeapObject::Class(class) => address(class),
+            HeapObject::Instance(instance) => address(instance),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            HeapObject::Scope(scope) => Rc::strong_count(scope),
+            HeapObject::Function(function) => Rc::strong_count(function),
+            HeapObject::Class(class) => Rc::strong_count(class),
+            HeapObject::Instance(instance) => Rc::strong_count(instance),
+        }
+    }
+
+    /// Calls `visit` with the name and address of each object this one refers to, once per
+    /// reference. The name is the variable, field or method holding the reference, or what the
+    /// reference is for. Returns false when the object is borrowed for writing, so its
+    /// references can't be seen.
+    fn references(&self, visit: &mut impl FnMut(&str, usize)) -> bool {
+        match self {
+            HeapObject::Scope(scope) => match scope.try_borrow() {
+                Ok(scope) => {
+                    if let Some(enclosing) = scope.enclosing() {
+                        visit("enclosing", address(enclosing));
+                    }
+                    scope
+                        .bindings()
+                        .for_each(|(name, value)| visit_value(name, value, visit));
+                    true
+                }
+                Err(_) => false,
+            },
+            HeapObject::Function(function) => {
+                visit("closure", address(&function.closure));
+                true
+            }
+            HeapObject::Class(class) => {
+                if let Some(superclass) = &class.superclass {
+                    visit("superclass", address(superclass));
+                }
+                class
+                    .methods
+                    .iter()
+                    .for_each(|(name, method)| visit(name, address(method)));
+                true
+            }
+            HeapObject::Instance(instance) => {
+                visit("class", address(&instance.class));
+                instance.visit_fields(|name, value| visit_value(name, value, visit))
+            }
+        }
+    }
+
+    /// What kind of object this is and a name to recognise it by in a heap snapshot.
+    fn describe(&self) -> (&'static str, String) {
+        match self {
+            HeapObject::Scope(scope) => {
+                let mut names: Vec<String> = match scope.try_borrow() {
+                    Ok(scope) => scope.bindings().map(|(name, _)| name.clone()).collect(),
+                    Err(_) => Vec::new(),
+                };
+                names.sort();
+                ("scope", names.join(", "))
+            }
+            HeapObject::Function(function) => ("function", Value::Function(Rc::clone(function)).to_string()),
+            HeapObject::Class(class) => ("class", Value::Class(Rc::clone(class)).to_string()),
+            HeapObject::Instance(instance) => ("instance", Value::Instance(Rc::clone(instance)).to_string()),
+        }
+    }
+
+    /// An estimate of the bytes the object takes up, including what it owns but not the objects
+    /// it refers to.
+    fn size(&self) -> usize {
+        let own = match self {
+            HeapObject::Scope(scope) => {
+                size_of::<RefCell<Scope>>()
+                    + scope
+                        .try_borrow()
+                        .map(|scope| {
+                            scope
+                                .bindings()
+                                .map(|(key, value)| binding_size(key, value))
+                                .sum()
+                        })
+                        .unwrap_or(0)
+            }
+            HeapObject::Function(_) => size_of::<LoxFunction>(),
+            HeapObject::Class(class) => {
+                size_of::<LoxClass>()
+                    + class.name.capacity()
+                    + class
+                        .methods
+                        .keys()
+                        .map(|key| size_of::<String>() + key.capacity() + size_of::<Rc<LoxFunction>>())
+                        .sum::<usize>()
+            }
+            HeapObject::Instance(instance) => {
+                let mut fields = 0;
+                instance.visit_fields(|key, value| fields += binding_size(key, value));
+                size_of::<LoxInstance>() + fields
+            }
+        };
+        RC_HEADER + own
+    }
+
+    /// Drops everything the object refers to that could lead back to it. Every cycle passes
+    /// through a scope or an instance's fields, so clearing those is enough to break them.
+    fn clear(&self) {
+        match self {
+            HeapObject::Scope(scope) => {
+                if let Ok(mut scope) = scope.try_borrow_mut() {
+                    scope.clear();
+                }
+            }
+            HeapObject::Instance(instance) => instance.clear_fields(),
+            HeapObject::Function(_) | HeapObject::Class(_) => (),
+        }
+    }
+}
+
+fn address<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const u8 as usize
+}
+
+fn visit_value(name: &str, value: &Value, visit: &mut impl FnMut(&str, usize)) {
+    match value {
+        Value::Function(function) => visit(name, address(function)),
+        Value::Class(class) => visit(name, address(class)),
+        Value::Instance(instance) => visit(name, address(instance)),
+        Value::Nil | Value::Boolean(_) | Value::Number(_) | Value::String(_) | Value::NativeFunction(_) => (),
+    }
+}
+
+fn binding_size(key: &str, value: &Value) -> usize {
+    let string = match value {
+        Value::String(string) => string.capacity(),
+        _ => 0,
+    };
+    size_of::<String>() + key.len() + size_of::<Value>() + string
+}
+
+/// Where the tree-walking interpreter's scopes, functions, classes and instances live. They are
+/// shared with `Rc`, which can't free a cycle such as a function stored in the scope it closes
+/// over, so the heap keeps every one of them and from time to time runs a mark-and-sweep
+/// collection to find the ones that can't be reached any more.
+///
+/// The roots are found rather than listed. Anything referred to from outside the heap, which is
+/// the environment's globals and current scope, the scopes of the calls in progress and any
+/// value the interpreter is in the middle of using, has more strong references than the heap
+/// can account for. Marking traces from those through enclosing scopes, closures, methods and
+/// fields, and the objects left unmarked are garbage. Sweeping clears their scopes and fields,
+/// which breaks their cycles, and lets go of them.
+#[derive(Default)]
+pub struct Heap {
+    objects: Vec<HeapObject>,
+    budget: GcBudget,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap::default()
+    }
+
+    pub fn config(&self) -> GcConfig {
+        self.budget.config()
+    }
+
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.budget.set_config(config);
+    }
+
+    /// How many objects are on the heap, including garbage that hasn't been collected yet.
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// An estimate of the bytes allocated, as of the last collection plus what has been
+    /// allocated since.
+    pub fn bytes_allocated(&self) -> usize {
+        self.budget.bytes_allocated()
+    }
+
+    pub fn alloc_scope(&mut self, enclosing: Option<&ScopeRef>) -> ScopeRef {
+        let scope = Rc::new(RefCell::new(Scope::new(enclosing.cloned())));
+        self.track(HeapObject::Scope(Rc::clone(&scope)));
+        scope
+    }
+
+    pub fn alloc_function(&mut self, function: LoxFunction) -> Rc<LoxFunction> {
+        let function = Rc::new(function);
+        self.track(HeapObject::Function(Rc::clone(&function)));
+        function
+    }
+
+    pub fn alloc_class(&mut self, class: LoxClass) -> Rc<LoxClass> {
+        let class = Rc::new(class);
+        self.track(HeapObject::Class(Rc::clone(&class)));
+        class
+    }
+
+    pub fn alloc_instance(&mut self, instance: LoxInstance) -> Rc<LoxInstance> {
+        let instance = Rc::new(instance);
+        self.track(HeapObject::Instance(Rc::clone(&instance)));
+        instance
+    }
+
+    // The new object is held by the caller while this collects, so it and everything it refers
+    // to count as roots.
+    fn track(&mut self, object: HeapObject) {
+        if self.budget.should_collect() {
+            self.collect();
+        }
+        self.budget.allocated(object.size());
+        self.objects.push(object);
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.budget.stats(self.len())
+    }
+
+    /// Frees every object that can't be reached from outside the heap.
+    pub fn collect(&mut self) {
+        let start = Instant::now();
+        let index = self.index();
+        let (internal, untraced) = self.count_references(&index);
+        // An object borrowed for writing is in use, and so are the objects it refers to, which
+        // look like roots themselves since their references weren't counted.
+        let roots = untraced.into_iter().chain(self.held_outside(&internal, &[]));
+
+        let mut marked = vec![false; self.objects.len()];
+        let mut gray = Vec::new();
+        for root in roots {
+            if !marked[root] {
+                marked[root] = true;
+                gray.push(root);
+            }
+        }
+        while let Some(i) = gray.pop() {
+            self.objects[i].references(&mut |_, address| {
+                if let Some(&j) = index.get(&address) {
+                    if !marked[j] {
+                        marked[j] = true;
+                        gray.push(j);
+                    }
+                }
+            });
+        }
+
+        let mut live_bytes = 0;
+        let mut freed = 0;
+        let objects = std::mem::take(&mut self.objects);
+        for (object, marked) in objects.into_iter().zip(marked) {
+            match marked {
+                true => {
+                    live_bytes += object.size();
+                    self.objects.push(object);
+                }
+                false => {
+                    object.clear();
+                    freed += 1;
+                }
+            }
+        }
+        self.budget.collected(live_bytes, freed, start.elapsed());
+    }
+
+    /// Describes every object on the heap, what it refers to and the shortest path to it from
+    /// a root. The named roots are scopes the caller holds, such as the globals, and come first.
+    /// Anything else referred to from outside the heap is a root named "external". Objects
+    /// without a path are garbage that hasn't been collected yet.
+    pub fn snapshot(&self, named_roots: &[(&str, &ScopeRef)]) -> HeapSnapshot {
+        let index = self.index();
+        let (internal, _) = self.count_references(&index);
+        let named: Vec<(String, usize)> = named_roots
+            .iter()
+            .filter_map(|(name, scope)| index.get(&address(scope)).map(|&i| (name.to_string(), i)))
+            .collect();
+        let known: Vec<usize> = named.iter().map(|(_, i)| *i).collect();
+        let external = self
+            .held_outside(&internal, &known)
+            .map(|i| ("external".to_string(), i));
+        let roots: Vec<SnapshotRoot> = named
+            .into_iter()
+            .chain(external)
+            .map(|(name, object)| SnapshotRoot { name, object })
+            .collect();
+
+        let mut objects: Vec<SnapshotObject> = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(id, object)| {
+                let (kind, name) = object.describe();
+                let mut references = Vec::new();
+                object.references(&mut |name, address| {
+                    if let Some(&to) = index.get(&address) {
+                        references.push(SnapshotReference {
+                            name: name.to_string(),
+                            object: to,
+                        });
+                    }
+                });
+                SnapshotObject {
+                    id,
+                    kind,
+                    name,
+                    size: object.size(),
+                    references,
+                    retaining_path: None,
+                }
+            })
+            .collect();
+
+        // Searching breadth first from the roots finds the shortest path to each object.
+        let mut queue = VecDeque::new();
+        for root in &roots {
+            if objects[root.object].retaining_path.is_none() {
+                objects[root.object].retaining_path = Some(vec![root.name.clone()]);
+                queue.push_back(root.object);
+            }
+        }
+        while let Some(i) = queue.pop_front() {
+            let path = objects[i].retaining_path.clone().unwrap_or_default();
+            for reference in objects[i].references.clone() {
+                let target = &mut objects[reference.object];
+                if target.retaining_path.is_none() {
+                    let mut path = path.clone();
+                    path.push(reference.name);
+                    target.retaining_path = Some(path);
+                    queue.push_back(reference.object);
+                }
+            }
+        }
+
+        HeapSnapshot {
+            stats: self.stats(),
+            roots,
+            objects,
+        }
+    }
+
+    fn index(&self) -> HashMap<usize, usize> {
+        self.objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.address(), i))
+            .collect()
+    }
+
+    /// Counts the references each object gets from other objects on the heap, and lists the
+    /// objects whose references couldn't be seen.
+    fn count_references(&self, index: &HashMap<usize, usize>) -> (Vec<usize>, Vec<usize>) {
+        let mut internal = vec![0; self.objects.len()];
+        let mut untraced = Vec::new();
+        for (i, object) in self.objects.iter().enumerate() {
+            let traced = object.references(&mut |_, address| {
+                if let Some(&j) = index.get(&address) {
+                    internal[j] += 1;
+                }
+            });
+            if !traced {
+                untraced.push(i);
+            }
+        }
+        (internal, untraced)
+    }
+
+    /// The objects with more strong references than the heap's own, those from other objects
+    /// on it and the `known` ones from outside. Something else is holding on to them.
+    fn held_outside<'a>(
+        &'a self,
+        internal: &'a [usize],
+        known: &'a [usize],
+    ) -> impl Iterator<Item = usize> + 'a {
+        (0..self.objects.len()).filter(move |&i| {
+            let known = known.iter().filter(|&&k| k == i).count();
+            self.objects[i].strong_count() > internal[i] + known + 1
+        })
+    }
+}
+
+// Objects that are only kept alive by each other would otherwise leak when the heap goes away.
+impl Drop for Heap {
+    fn drop(&mut self) {
+        self.collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::Environment;
+    use crate::interpreter::{Interpreter, InterpreterState};
+    use crate::runhelpers::raw_source_to_ast;
+
+    fn run(state: &mut InterpreterState<Vec<u8>>, source: &str) {
+        let statements = raw_source_to_ast(source, "unittest.lox").unwrap();
+        assert_eq!(Interpreter::new(statements).interpret(state), vec![]);
+    }
+
+    #[test]
+    fn test_collects_unreachable_cycles() {
+        let mut state = InterpreterState::default();
+        run(
+            &mut state,
+            "fun make() { var self; fun f() { return self; } self = f; } for (var i = 0; i < 100; i = i + 1) make();",
+        );
+        let before = state.heap().len();
+        state.heap_mut().collect();
+        assert!(
+            state.heap().len() < before / 10,
+            "{} objects left",
+            state.heap().len()
+        );
+    }
+
+    #[test]
+    fn test_keeps_reachable_objects() {
+        let mut state = InterpreterState::default();
+        run(
+            &mut state,
+            "class Node { init(next) { this.next = next; this.self = this; } }
+             var list = Node(Node(nil));
+             fun counter() { var i = 0; fun inc() { i = i + 1; return i; } return inc; }
+             var c = counter(); c();",
+        );
+        state.heap_mut().collect();
+        run(&mut state, "print list.next.self.next; print c();");
+        assert_eq!(state.get_writer(), "nil\n2\n");
+    }
+
+    #[test]
+    fn test_keeps_objects_held_outside_the_heap() {
+        let mut env = Environment::new();
+        env.new_child_scope();
+        env.define("x".to_string(), Value::Number(1.0));
+        let captured = env.capture();
+        env.destroy_child_scope();
+        env.heap_mut().collect();
+        assert_eq!(captured.borrow().get("x"), Some(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_stress_mode_runs_programs_unchanged() {
+        let mut state = InterpreterState::default();
+        state.heap_mut().set_config(GcConfig::stress());
+        run(
+            &mut state,
+            "class A { init(n) { this.n = n; } get() { return this.n; } }
+             class B < A { get() { return super.get() * 2; } }
+             fun twice(f, x) { return f(f(x)); }
+             fun add(x) { return B(x).get() + 1; }
+             print twice(add, 1) + A(3).get();",
+        );
+        assert_eq!(state.get_writer(), "10\n");
+    }
+
+    #[test]
+    fn test_collects_when_over_the_threshold() {
+        let mut state = InterpreterState::default();
+        state.heap_mut().set_config(GcConfig {
+            threshold: 4096,
+            ..GcConfig::default()
+        });
+        run(
+            &mut state,
+            "fun f() { fun g() {} } for (var i = 0; i < 1000; i = i + 1) f();",
+        );
+        assert!(state.heap().len() < 1000, "{} objects left", state.heap().len());
+    }
+
+    #[test]
+    fn test_stats_count_allocations_and_collections() {
+        let mut state = InterpreterState::default();
+        state.heap_mut().set_config(GcConfig {
+            threshold: 4096,
+            ..GcConfig::default()
+        });
+        run(
+            &mut state,
+            "fun f() { fun g() {} } for (var i = 0; i < 1000; i = i + 1) f();",
+        );
+        let stats = state.gc_stats();
+        assert!(stats.allocations >= 2000, "{:?}", stats);
+        assert!(stats.collections > 0, "{:?}", stats);
+        assert!(stats.objects_freed > 0, "{:?}", stats);
+        assert!(stats.bytes_allocated > stats.bytes_in_use as u64, "{:?}", stats);
+        assert_eq!(stats.objects, state.heap().len());
+        assert!(stats.max_pause <= stats.total_pause);
+    }
+
+    #[test]
+    fn test_snapshot_shows_what_keeps_objects_alive() {
+        let mut state = InterpreterState::default();
+        run(
+            &mut state,
+            "class Node { init(next) { this.next = next; } }
+             var list = Node(Node(nil));
+             fun make() { var self; fun f() { return self; } self = f; } make();",
+        );
+        let snapshot = state.heap_snapshot();
+        assert_eq!(snapshot.stats, state.gc_stats());
+        assert_eq!(snapshot.roots[0].name, "globals");
+
+        let instances: Vec<_> = snapshot
+            .objects
+            .iter()
+            .filter(|object| object.kind == "instance")
+            .collect();
+        assert_eq!(instances.len(), 2);
+        let paths: Vec<_> = instances
+            .iter()
+            .map(|instance| instance.retaining_path.clone().unwrap())
+            .collect();
+        assert!(paths.contains(&vec!["globals".to_string(), "list".to_string()]));
+        assert!(paths.contains(&vec![
+            "globals".to_string(),
+            "list".to_string(),
+            "next".to_string()
+        ]));
+        assert!(instances.iter().all(|instance| instance.size > 0));
+
+        // The closure made by `make` is garbage, kept alive only by its own scope.
+        let garbage: Vec<_> = snapshot
+            .objects
+            .iter()
+            .filter(|object| object.retaining_path.is_none())
+            .map(|object| object.kind)
+            .collect();
+        assert!(garbage.contains(&"function"), "{:?}", garbage);
+    }
+}
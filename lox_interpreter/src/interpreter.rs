@@ -1,31 +1,49 @@
+use std::collections::HashMap;
 use std::io::Write;
+use std::rc::Rc;
 
 use crate::core::errors::LoxError;
 use crate::environment::Environment;
-use crate::parser::{Expr, Literal, Stmt};
-use crate::tokens::TokenType;
-use crate::value::Value;
+use crate::gc::GcStats;
+use crate::heap::Heap;
+use crate::natives;
+use crate::parser::{Expr, Literal, ScopeDepth, Stmt};
+use crate::resolver::Resolver;
+use crate::snapshot::HeapSnapshot;
+use crate::tokens::{Token, TokenType};
+use crate::value::{LoxClass, LoxFunction, LoxInstance, NativeFunction, Value};
+
+/// What to do with the remaining top level statements after one fails with a runtime error.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ErrorPolicy {
+    /// Stop at the first runtime error, like the reference implementation.
+    #[default]
+    Abort,
+    /// Report the error and carry on with the next top level statement.
+    Continue,
+}
+
+/// How deep calls can nest before the script fails with a stack overflow, the same limit as the
+/// bytecode VM's. Each call recurses on the Rust stack, so the limit has to be reached before the
+/// host's stack runs out.
+pub const MAX_CALL_DEPTH: usize = 1024;
 
 pub struct InterpreterState<W: Write> {
     environment: Environment,
     writer: W,
+    error_policy: ErrorPolicy,
+    call_depth: usize,
 }
 
 impl Default for InterpreterState<std::io::Stdout> {
     fn default() -> Self {
-        InterpreterState {
-            environment: Environment::new(),
-            writer: std::io::stdout(),
-        }
+        InterpreterState::new(std::io::stdout())
     }
 }
 
 impl Default for InterpreterState<Vec<u8>> {
     fn default() -> Self {
-        InterpreterState {
-            environment: Environment::new(),
-            writer: Vec::new(),
-        }
+        InterpreterState::new(Vec::new())
     }
 }
 
@@ -35,6 +53,87 @@ impl InterpreterState<Vec<u8>> {
     }
 }
 
+impl<W: Write> InterpreterState<W> {
+    /// A state whose globals hold just the built-in native functions.
+    pub fn new(writer: W) -> Self {
+        InterpreterState {
+            environment: natives::global_environment(),
+            writer,
+            error_policy: ErrorPolicy::default(),
+            call_depth: 0,
+        }
+    }
+
+    pub fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.error_policy = error_policy;
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    pub fn writer(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// The heap scripts allocate their functions, classes, instances and scopes on.
+    pub fn heap(&self) -> &Heap {
+        self.environment.heap()
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        self.environment.heap_mut()
+    }
+
+    /// How much the heap has allocated and collected, and how long collections took.
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap().stats()
+    }
+
+    /// Every object on the heap with its size, what it refers to and what is keeping it alive.
+    pub fn heap_snapshot(&self) -> HeapSnapshot {
+        self.environment.snapshot()
+    }
+
+    /// Writes `heap_snapshot` to `path` as JSON.
+    pub fn write_heap_snapshot(&self, path: &str) -> Result<(), LoxError> {
+        self.heap_snapshot().write_file(path)
+    }
+
+    /// Throws away every binding, leaving the state as if it had just been created apart from
+    /// how its heap is tuned.
+    pub fn reset(&mut self) {
+        let config = self.heap().config();
+        self.environment = natives::global_environment();
+        self.call_depth = 0;
+        self.heap_mut().set_config(config);
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.environment.define_global(name.to_string(), value);
+    }
+
+    /// Makes a Rust function callable from scripts as the global `name`. It is always called with
+    /// exactly `arity` arguments and an `Err` it returns becomes a runtime error at the call.
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        let native = NativeFunction::new(name, arity, function);
+        self.define_global(name, Value::NativeFunction(Rc::new(native)));
+    }
+}
+
+/// How a statement finished executing. `Return` carries the returned value up through any
+/// enclosing blocks and loops until it reaches the function call that is being executed, while
+/// `Break` and `Continue` only travel as far as the innermost loop.
+enum Completion {
+    Normal,
+    Return(Value),
+    Break,
+    Continue,
+}
+
 pub struct Interpreter {
     statements: Vec<Stmt>,
 }
@@ -44,34 +143,58 @@ impl Interpreter {
         Interpreter { statements }
     }
 
+    /// Resolves the statements and then executes them. Nothing is executed if the resolver finds
+    /// any errors.
     pub fn interpret<T: Write>(&self, state: &mut InterpreterState<T>) -> Vec<LoxError> {
-        let mut errors: Vec<LoxError> = Vec::new();
+        let (_, errors) = self.interpret_with_value(state);
+        errors
+    }
+
+    /// Like `interpret`, but when the last statement is an expression statement its value is
+    /// returned too. The REPL uses this to echo the value of bare expressions.
+    pub fn interpret_with_value<T: Write>(
+        &self,
+        state: &mut InterpreterState<T>,
+    ) -> (Option<Value>, Vec<LoxError>) {
+        let mut errors = Resolver::new().resolve(&self.statements);
+        if !errors.is_empty() {
+            return (None, errors);
+        }
+        let mut last_value: Option<Value> = None;
         for stmt in &self.statements {
-            let result = self.evaluate(stmt, state);
-            match result {
-                Ok(_) => (),
-                Err(err) => errors.push(err),
+            last_value = None;
+            let result = match stmt {
+                Stmt::Expression(expr) => self
+                    .evaluate_expr(expr, state)
+                    .map(|value| last_value = Some(value)),
+                _ => self.evaluate(stmt, state).map(|_| ()),
+            };
+            if let Err(err) = result {
+                errors.push(err);
+                if state.error_policy == ErrorPolicy::Abort {
+                    break;
+                }
             }
         }
-        errors
+        (last_value, errors)
     }
 
     fn evaluate<T: Write>(
         &self,
         stmt: &Stmt,
         state: &mut InterpreterState<T>,
-    ) -> Result<Option<Value>, LoxError> {
+    ) -> Result<Completion, LoxError> {
         match stmt {
             Stmt::Expression(expr) => {
-                let value = self.evaluate_expr(expr, state)?;
-                Ok(Some(value))
+                self.evaluate_expr(expr, state)?;
+                Ok(Completion::Normal)
             }
             Stmt::Print(expr) => self.evaluate_print(expr, state),
             Stmt::Var(identifier_token, initializer) => {
                 if let TokenType::Identifier(name) = &identifier_token.token_type {
                     let value = self.evaluate_expr(initializer, state)?;
                     state.environment.define(name.to_string(), value);
-                    Ok(None)
+                    Ok(Completion::Normal)
                 } else {
                     Err(LoxError::RuntimeError(
                         identifier_token.location.clone(),
@@ -79,53 +202,221 @@ impl Interpreter {
                     ))
                 }
             }
-            Stmt::Block(statements) => {
-                state.environment.new_child_scope();
-                self.execute_block(statements, state)?;
-                state.environment.destroy_child_scope();
-                Ok(None)
-            }
+            Stmt::Block(statements) => self.execute_block(statements, state),
             Stmt::If(condition, left_stmt, right_stmt) => {
                 match self.evaluate_expr(condition, state)?.is_truthy() {
                     true => self.evaluate(left_stmt, state),
                     false => match right_stmt {
                         Some(x) => self.evaluate(x, state),
-                        None => Ok(None),
+                        None => Ok(Completion::Normal),
                     },
                 }
             }
-            Stmt::While(condition, body) => {
+            Stmt::While(condition, body, increment) => {
                 while self.evaluate_expr(condition, state)?.is_truthy() {
-                    self.evaluate(body, state)?;
+                    match self.evaluate(body, state)? {
+                        Completion::Return(value) => return Ok(Completion::Return(value)),
+                        Completion::Break => break,
+                        Completion::Normal | Completion::Continue => (),
+                    }
+                    if let Some(increment) = increment {
+                        self.evaluate_expr(increment, state)?;
+                    }
                 }
-                Ok(None)
+                Ok(Completion::Normal)
+            }
+            Stmt::Break(_) => Ok(Completion::Break),
+            Stmt::Continue(_) => Ok(Completion::Continue),
+            Stmt::Function(declaration) => {
+                let function = LoxFunction::new(Rc::clone(declaration), state.environment.capture());
+                let function = state.heap_mut().alloc_function(function);
+                state
+                    .environment
+                    .define(function.name(), Value::Function(function));
+                Ok(Completion::Normal)
+            }
+            Stmt::Return(_, expr) => {
+                let value = match expr {
+                    Some(expr) => self.evaluate_expr(expr, state)?,
+                    None => Value::Nil,
+                };
+                Ok(Completion::Return(value))
+            }
+            Stmt::Class(name, superclass_expr, method_decls) => {
+                let superclass = match superclass_expr {
+                    Some(expr) => match self.evaluate_expr(expr, state)? {
+                        Value::Class(class) => Some(class),
+                        _ => {
+                            return Err(LoxError::RuntimeError(
+                                expr.location(),
+                                "Superclass must be a class.".to_string(),
+                            ));
+                        }
+                    },
+                    None => None,
+                };
+
+                // Methods of a subclass close over an extra scope that binds `super`.
+                if let Some(superclass) = &superclass {
+                    state.environment.new_child_scope();
+                    state
+                        .environment
+                        .define("super".to_string(), Value::Class(Rc::clone(superclass)));
+                }
+                let closure = state.environment.capture();
+                let mut methods: HashMap<String, Rc<LoxFunction>> = HashMap::new();
+                for method_decl in method_decls {
+                    let method = LoxFunction::new_method(Rc::clone(method_decl), Rc::clone(&closure));
+                    methods.insert(method.name(), state.heap_mut().alloc_function(method));
+                }
+                if superclass.is_some() {
+                    state.environment.destroy_child_scope();
+                }
+
+                let class = LoxClass::new(name.token_type.to_string(), superclass, methods);
+                let class = state.heap_mut().alloc_class(class);
+                state.environment.define(class.name.clone(), Value::Class(class));
+                Ok(Completion::Normal)
             }
         }
     }
 
+    /// Runs the statements in a new child scope. The scope is destroyed however the block exits,
+    /// whether it runs to the end, returns early or fails with an error.
     fn execute_block<T: Write>(
         &self,
-        statements: &Vec<Stmt>,
+        statements: &[Stmt],
         state: &mut InterpreterState<T>,
-    ) -> Result<(), LoxError> {
+    ) -> Result<Completion, LoxError> {
+        state.environment.new_child_scope();
+        let result = self.execute_statements(statements, state);
+        state.environment.destroy_child_scope();
+        result
+    }
+
+    fn execute_statements<T: Write>(
+        &self,
+        statements: &[Stmt],
+        state: &mut InterpreterState<T>,
+    ) -> Result<Completion, LoxError> {
         for stmt in statements {
-            self.evaluate(stmt, state)?;
+            match self.evaluate(stmt, state)? {
+                Completion::Normal => (),
+                completion => return Ok(completion),
+            }
+        }
+        Ok(Completion::Normal)
+    }
+
+    fn call_function<T: Write>(
+        &self,
+        function: &LoxFunction,
+        arguments: Vec<Value>,
+        paren: &Token,
+        state: &mut InterpreterState<T>,
+    ) -> Result<Value, LoxError> {
+        if arguments.len() != function.arity() {
+            let msg = format!(
+                "Expected {} arguments but got {}.",
+                function.arity(),
+                arguments.len()
+            );
+            return Err(LoxError::RuntimeError(paren.location.clone(), msg));
+        }
+
+        if state.call_depth == MAX_CALL_DEPTH {
+            return Err(LoxError::RuntimeError(
+                paren.location.clone(),
+                "Stack overflow.".to_string(),
+            ));
+        }
+
+        state.call_depth += 1;
+        let previous = state.environment.enter_closure(&function.closure);
+        for (param, argument) in function.declaration.params.iter().zip(arguments) {
+            state.environment.define(param.token_type.to_string(), argument);
+        }
+        let result = self.execute_statements(&function.declaration.body, state);
+        state.environment.restore_scope(previous);
+        state.call_depth -= 1;
+
+        // Each call an error passes through adds a line to its stack trace.
+        let result = result.map_err(|err| {
+            let call_site = paren.location.position().unwrap_or_default();
+            err.with_frame(&format!("in {}(), called at {}", function.name(), call_site))
+        });
+        match (result?, function.is_initializer) {
+            // An initializer always returns the instance, including from a bare `return;`.
+            (_, true) => Ok(function.closure.borrow().get("this").unwrap_or(Value::Nil)),
+            (Completion::Return(value), false) => Ok(value),
+            // The parser rejects `break` and `continue` that aren't inside a loop in the function.
+            (_, false) => Ok(Value::Nil),
+        }
+    }
+
+    fn call_class<T: Write>(
+        &self,
+        class: Rc<LoxClass>,
+        arguments: Vec<Value>,
+        paren: &Token,
+        state: &mut InterpreterState<T>,
+    ) -> Result<Value, LoxError> {
+        let instance = state
+            .heap_mut()
+            .alloc_instance(LoxInstance::new(Rc::clone(&class)));
+        match class.find_method("init") {
+            Some(initializer) => {
+                let bound = initializer.bind(Rc::clone(&instance), state.heap_mut());
+                self.call_function(&bound, arguments, paren, state)?;
+            }
+            None if !arguments.is_empty() => {
+                let msg = format!("Expected 0 arguments but got {}.", arguments.len());
+                return Err(LoxError::RuntimeError(paren.location.clone(), msg));
+            }
+            None => (),
+        }
+        Ok(Value::Instance(instance))
+    }
+
+    fn call_native(
+        &self,
+        native: &NativeFunction,
+        arguments: &[Value],
+        paren: &Token,
+    ) -> Result<Value, LoxError> {
+        if arguments.len() != native.arity {
+            let msg = format!("Expected {} arguments but got {}.", native.arity, arguments.len());
+            return Err(LoxError::RuntimeError(paren.location.clone(), msg));
+        }
+        native
+            .call(arguments)
+            .map_err(|msg| LoxError::RuntimeError(paren.location.clone(), msg))
+    }
+
+    fn look_up_variable<T: Write>(
+        &self,
+        name: &str,
+        depth: &ScopeDepth,
+        state: &InterpreterState<T>,
+    ) -> Option<Value> {
+        match depth.get() {
+            Some(depth) => state.environment.get_at(depth, name),
+            None => state.environment.get_global(name),
         }
-        Ok(())
     }
 
     fn evaluate_print<T: Write>(
         &self,
         expr: &Expr,
         state: &mut InterpreterState<T>,
-    ) -> Result<Option<Value>, LoxError> {
+    ) -> Result<Completion, LoxError> {
         let value = self.evaluate_expr(expr, state)?;
         // writeln!(state.writer, "{}", value);
         if let Err(err) = writeln!(state.writer, "{}", value) {
             let wrapped_syscall_error = LoxError::new_syscall(std::file!(), 111, err.to_string());
             return Err(wrapped_syscall_error);
         }
-        Ok(None)
+        Ok(Completion::Normal)
     }
 
     fn evaluate_expr<T: Write>(
@@ -218,26 +509,33 @@ impl Interpreter {
                     )),
                 }
             }
-            Expr::Variable(token) => match &token.token_type {
-                TokenType::Identifier(variable_name) => match state.environment.get(variable_name) {
-                    Some(val) => Ok(val),
-                    None => Err(LoxError::RuntimeError(
-                        token.location.clone(),
-                        format!("Undefined variable: {}", variable_name),
-                    )),
-                },
+            Expr::Variable(token, depth) => match &token.token_type {
+                TokenType::Identifier(variable_name) => {
+                    self.look_up_variable(variable_name, depth, state).ok_or_else(|| {
+                        LoxError::RuntimeError(
+                            token.location.clone(),
+                            format!("Undefined variable: {}", variable_name),
+                        )
+                    })
+                }
                 _ => Err(LoxError::RuntimeError(
                     token.location.clone(),
                     format!("Expected a variable expression. Got {}", token),
                 )),
             },
-            Expr::Assign(token, expr) => {
+            Expr::Assign(token, expr, depth) => {
                 let value = self.evaluate_expr(expr, state)?;
                 match &token.token_type {
                     TokenType::Identifier(name) => {
-                        state
-                            .environment
-                            .assign(name, value.clone(), token.location.clone())?;
+                        let location = token.location.clone();
+                        match depth.get() {
+                            Some(depth) => {
+                                state
+                                    .environment
+                                    .assign_at(depth, name, value.clone(), location)?
+                            }
+                            None => state.environment.assign_global(name, value.clone(), location)?,
+                        };
                         Ok(value)
                     }
                     _ => Err(LoxError::RuntimeError(
@@ -246,7 +544,87 @@ impl Interpreter {
                     )),
                 }
             }
-            _ => Err(LoxError::Critical("Happening in the interpreter.".to_string())),
+            Expr::Call(callee_expr, paren, argument_exprs) => {
+                let callee = self.evaluate_expr(callee_expr, state)?;
+                let mut arguments: Vec<Value> = Vec::new();
+                for argument in argument_exprs {
+                    arguments.push(self.evaluate_expr(argument, state)?);
+                }
+                match callee {
+                    Value::Function(function) => self.call_function(&function, arguments, paren, state),
+                    Value::Class(class) => self.call_class(class, arguments, paren, state),
+                    Value::NativeFunction(native) => self.call_native(&native, &arguments, paren),
+                    _ => Err(LoxError::RuntimeError(
+                        paren.location.clone(),
+                        "Can only call functions and classes.".to_string(),
+                    )),
+                }
+            }
+            Expr::Get(object_expr, name) => match self.evaluate_expr(object_expr, state)? {
+                Value::Instance(instance) => {
+                    let property = name.token_type.to_string();
+                    instance.get(&property, state.heap_mut()).ok_or_else(|| {
+                        LoxError::RuntimeError(
+                            name.location.clone(),
+                            format!("Undefined property '{}'.", property),
+                        )
+                    })
+                }
+                _ => Err(LoxError::RuntimeError(
+                    name.location.clone(),
+                    "Only instances have properties.".to_string(),
+                )),
+            },
+            Expr::Set(object_expr, name, value_expr) => match self.evaluate_expr(object_expr, state)? {
+                Value::Instance(instance) => {
+                    let value = self.evaluate_expr(value_expr, state)?;
+                    instance.set(&name.token_type.to_string(), value.clone());
+                    Ok(value)
+                }
+                _ => Err(LoxError::RuntimeError(
+                    name.location.clone(),
+                    "Only instances have fields.".to_string(),
+                )),
+            },
+            Expr::This(keyword, depth) => self.look_up_variable("this", depth, state).ok_or_else(|| {
+                LoxError::RuntimeError(
+                    keyword.location.clone(),
+                    "Can't use 'this' outside of a class.".to_string(),
+                )
+            }),
+            Expr::Super(keyword, method_name, depth) => {
+                // The scope binding `this` is always the one just inside the scope binding `super`.
+                let (super_value, this_value) = match depth.get() {
+                    Some(depth) if depth > 0 => (
+                        state.environment.get_at(depth, "super"),
+                        state.environment.get_at(depth - 1, "this"),
+                    ),
+                    _ => (None, None),
+                };
+                let (superclass, this) = match (super_value, this_value) {
+                    (Some(Value::Class(superclass)), Some(Value::Instance(this))) => (superclass, this),
+                    _ => {
+                        return Err(LoxError::RuntimeError(
+                            keyword.location.clone(),
+                            "Can't use 'super' outside of a class with a superclass.".to_string(),
+                        ))
+                    }
+                };
+                let name = method_name.token_type.to_string();
+                match superclass.find_method(&name) {
+                    Some(method) => Ok(Value::Function(method.bind(this, state.heap_mut()))),
+                    None => Err(LoxError::RuntimeError(
+                        method_name.location.clone(),
+                        format!("Undefined property '{}'.", name),
+                    )),
+                }
+            }
+            Expr::Ternary(condition, then_branch, else_branch) => {
+                match self.evaluate_expr(condition, state)?.is_truthy() {
+                    true => self.evaluate_expr(then_branch, state),
+                    false => self.evaluate_expr(else_branch, state),
+                }
+            }
         }
     }
 }
@@ -1,9 +1,20 @@
 #![allow(clippy::match_like_matches_macro)]
+pub mod bytecode;
+pub mod conformance;
 pub mod core;
 pub mod environment;
+pub mod gc;
+pub mod heap;
 pub mod interpreter;
+pub mod lox;
+pub mod natives;
 pub mod parser;
+pub mod repl;
+pub mod resolver;
 pub mod runhelpers;
 pub mod scanner;
+pub mod snapshot;
 pub mod tokens;
 pub mod value;
+
+pub use lox::Lox;
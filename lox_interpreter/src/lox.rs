@@ -0,0 +1,228 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+use crate::core::errors::LoxError;
+use crate::core::sources::Sources;
+use crate::gc::GcConfig;
+use crate::interpreter::{ErrorPolicy, Interpreter, InterpreterState};
+use crate::parser::Parser;
+use crate::scanner::SourceCode;
+use crate::value::Value;
+
+/// An embeddable Lox engine. Globals defined by one call to `eval` or `run_file` can be used by
+/// the next, so a host can build up a session a piece at a time.
+///
+/// Scripts print to the engine's writer and the `readLine()` native reads a line from its reader,
+/// returning `nil` once the reader is exhausted.
+pub struct Lox<W: Write> {
+    state: InterpreterState<W>,
+    sources: Sources,
+    evals: usize,
+}
+
+impl Lox<std::io::Stdout> {
+    /// An engine that prints to stdout and reads from stdin.
+    pub fn new() -> Self {
+        let stdin = std::io::BufReader::new(std::io::stdin());
+        Lox::with_io(std::io::stdout(), stdin)
+    }
+}
+
+impl Default for Lox<std::io::Stdout> {
+    fn default() -> Self {
+        Lox::new()
+    }
+}
+
+impl<W: Write> Lox<W> {
+    pub fn with_io<R: BufRead + 'static>(writer: W, reader: R) -> Self {
+        let mut lox = Lox {
+            state: InterpreterState::new(writer),
+            sources: Sources::default(),
+            evals: 0,
+        };
+        lox.set_stdin(reader);
+        lox
+    }
+
+    /// Replaces the reader that `readLine()` reads from.
+    pub fn set_stdin<R: BufRead + 'static>(&mut self, reader: R) {
+        let reader = Rc::new(RefCell::new(reader));
+        self.state.define_native("readLine", 0, move |_| {
+            let mut line = String::new();
+            match reader.borrow_mut().read_line(&mut line) {
+                Ok(0) => Ok(Value::Nil),
+                Ok(_) => Ok(Value::String(line.trim_end_matches(['\n', '\r']).to_string())),
+                Err(err) => Err(format!("Could not read a line: {}", err)),
+            }
+        });
+    }
+
+    /// Runs the source and returns the value of its last statement when that is an expression
+    /// statement, or `nil` otherwise. Nothing runs if the source has syntax errors, while what
+    /// happens after a runtime error depends on the error policy.
+    pub fn eval(&mut self, source: &str) -> Result<Value, Vec<LoxError>> {
+        self.evals += 1;
+        let filename = format!("eval[{}]", self.evals);
+        self.run_source(source, &filename)
+    }
+
+    pub fn run_file(&mut self, filepath: &str) -> Result<Value, Vec<LoxError>> {
+        match fs::read_to_string(filepath) {
+            Ok(source) => self.run_source(&source, filepath),
+            Err(err) => Err(vec![LoxError::new_syscall(filepath, 0, err.to_string())]),
+        }
+    }
+
+    /// Like `eval`, but errors refer to the source by the given filename.
+    pub fn run_source(&mut self, source: &str, filename: &str) -> Result<Value, Vec<LoxError>> {
+        self.sources.insert(filename, source);
+        let tokens = SourceCode::new(source, filename.to_string()).scan_tokens();
+        let (statements, errors) = Parser::new(tokens).parse_recovering();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let (value, errors) = Interpreter::new(statements).interpret_with_value(&mut self.state);
+        match errors.is_empty() {
+            true => Ok(value.unwrap_or(Value::Nil)),
+            false => Err(errors),
+        }
+    }
+
+    /// Chooses whether to stop at the first runtime error, which is the default, or carry on.
+    pub fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.state.set_error_policy(error_policy);
+    }
+
+    /// Tunes when the engine's heap collects garbage.
+    pub fn set_gc_config(&mut self, config: GcConfig) {
+        self.state.heap_mut().set_config(config);
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.state.define_global(name, value);
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.state.environment().get_global(name)
+    }
+
+    /// Makes a Rust function callable from scripts, see `InterpreterState::define_native`.
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        self.state.define_native(name, arity, function);
+    }
+
+    /// The source that was run under `filename`, for showing errors in context. Only the most
+    /// recent sources are kept, see `set_source_capacity`.
+    pub fn source(&self, filename: &str) -> Option<&str> {
+        self.sources.get(filename)
+    }
+
+    /// Sets how many of the most recent sources are kept for `source`. The default is
+    /// `DEFAULT_SOURCE_CAPACITY` and 0 keeps none, for hosts that don't show errors in context.
+    pub fn set_source_capacity(&mut self, capacity: usize) {
+        self.sources.set_capacity(capacity);
+    }
+
+    pub fn writer(&mut self) -> &mut W {
+        self.state.writer()
+    }
+
+    pub fn state(&self) -> &InterpreterState<W> {
+        &self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lox(input: &str) -> Lox<Vec<u8>> {
+        Lox::with_io(Vec::new(), std::io::Cursor::new(input.to_string()))
+    }
+
+    #[test]
+    fn test_eval_returns_last_expression() {
+        let mut lox = lox("");
+        assert_eq!(lox.eval("var a = 40;"), Ok(Value::Nil));
+        assert_eq!(lox.eval("a + 2;"), Ok(Value::Number(42.0)));
+        assert_eq!(lox.eval(""), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn test_eval_returns_errors() {
+        let mut lox = lox("");
+        let errors = lox.eval("print ;\nvar = 1;").unwrap_err();
+        assert_eq!(errors.len(), 2);
+
+        let errors = lox.eval("print nope;").unwrap_err();
+        assert_eq!(errors[0].message(), "Undefined variable: nope");
+        assert_eq!(lox.source("eval[2]"), Some("print nope;"));
+    }
+
+    #[test]
+    fn test_only_recent_sources_are_kept() {
+        let mut lox = lox("");
+        lox.set_source_capacity(2);
+        for _ in 0..3 {
+            lox.eval("1;").unwrap();
+        }
+        assert_eq!(lox.source("eval[1]"), None);
+        assert_eq!(lox.source("eval[3]"), Some("1;"));
+
+        lox.set_source_capacity(0);
+        assert_eq!(lox.source("eval[3]"), None);
+        let errors = lox.eval("print nope;").unwrap_err();
+        assert_eq!(errors[0].message(), "Undefined variable: nope");
+        assert_eq!(lox.source("eval[4]"), None);
+    }
+
+    #[test]
+    fn test_globals_can_be_set_and_read() {
+        let mut lox = lox("");
+        lox.set_global("name", Value::String("lox".to_string()));
+        lox.eval("var greeting = \"hello \" + name;").unwrap();
+        assert_eq!(
+            lox.get_global("greeting"),
+            Some(Value::String("hello lox".to_string()))
+        );
+        assert_eq!(lox.get_global("missing"), None);
+    }
+
+    #[test]
+    fn test_output_and_input_are_configurable() {
+        let mut lox = lox("first\nsecond\n");
+        lox.eval("print readLine(); print readLine(); print readLine();")
+            .unwrap();
+        assert_eq!(lox.writer().as_slice(), b"first\nsecond\nnil\n");
+    }
+
+    #[test]
+    fn test_run_file_that_does_not_exist() {
+        let mut lox = lox("");
+        let errors = lox.run_file("does/not/exist.lox").unwrap_err();
+        assert!(matches!(errors[..], [LoxError::Syscall(_, _)]));
+    }
+
+    #[test]
+    fn test_heap_snapshot_is_written_as_json() {
+        let mut lox = lox("");
+        lox.eval("class A {} var a = A();").unwrap();
+        let path = std::env::temp_dir().join(format!("rlox-snapshot-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        lox.state().write_heap_snapshot(path).unwrap();
+        let json = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(json.contains("\"kind\": \"instance\", \"name\": \"A instance\""));
+        assert!(json.contains("\"retaining_path\": [\"globals\", \"a\"]"));
+
+        let error = lox.state().write_heap_snapshot("does/not/exist.json");
+        assert!(matches!(error, Err(LoxError::Syscall(_, _))));
+    }
+}
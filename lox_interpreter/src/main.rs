@@ -1,96 +1,532 @@
 use std::env;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::thread;
 
-use lox_interpreter::interpreter::Interpreter;
-use lox_interpreter::interpreter::InterpreterState;
-use lox_interpreter::parser::parenthesize_statements;
-use lox_interpreter::parser::Parser;
-use lox_interpreter::runhelpers::load_source;
+use lox_interpreter::bytecode::chunk::Function;
+use lox_interpreter::bytecode::compiler;
+use lox_interpreter::bytecode::disassembler::disassemble;
+use lox_interpreter::bytecode::loxc;
+use lox_interpreter::bytecode::vm::Vm;
+use lox_interpreter::conformance::{self, Backend};
+use lox_interpreter::core::diagnostics::Diagnostic;
+use lox_interpreter::core::errors::LoxError;
+use lox_interpreter::gc::GcConfig;
+use lox_interpreter::interpreter::{ErrorPolicy, InterpreterState};
+use lox_interpreter::parser::{parenthesize_statements, Parser, Stmt};
+use lox_interpreter::repl::{Repl, ReplStatus};
+use lox_interpreter::resolver::Resolver;
 use lox_interpreter::scanner::SourceCode;
+use lox_interpreter::tokens::{Token, TokenType};
+use lox_interpreter::Lox;
 
-enum ReplMode {
-    Standard,
-    Debug,
+// Exit codes from sysexits.h, which the reference implementation uses too.
+const EXIT_USAGE: i32 = 64;
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_NO_INPUT: i32 = 66;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+const EXIT_CANT_CREATE: i32 = 73;
+
+// Scripts run on a thread with this much stack, enough for the deepest calls the interpreter
+// allows even in a debug build, where each call takes tens of kilobytes.
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+const USAGE: &str = "\
+Usage: rlox [command]
+
+Commands:
+  run [--keep-going | --vm | --trace] [--gc-stress] <file>
+                   Run a script. It stops at the first runtime error unless --keep-going is
+                   given, in which case the remaining top level statements still run.
+                   --vm compiles the script to bytecode and runs it on the virtual machine
+                   instead of the tree-walking interpreter. --trace runs it on the virtual
+                   machine too, printing the stack and each instruction as it runs.
+                   A .loxc file made by compile always runs on the virtual machine.
+                   --gc-stress collects garbage before every allocation, which is slow but
+                   shows up objects that are freed while they are still in use.
+  repl             Start an interactive prompt. This is the default when no command is given.
+  tokens <file>    Print the tokens the scanner produces.
+  ast <file>       Print the parenthesized syntax tree.
+  disasm <file>    Print the bytecode the script compiles to, with the source lines it came
+                   from.
+  compile <file> [-o <out>]
+                   Compile a script to a .loxc file that run can load without parsing it
+                   again. The output defaults to the file with its extension changed.
+  check <file>     Report syntax and resolution errors without running anything.
+  test [--vm] [--gc-stress] <dir>
+                   Run every .lox script under the directory and check it does what its
+                   // expect comments say.
+  help             Print this message.
+
+Use - as the file to read the source from stdin.";
+
+enum Command {
+    Run {
+        file: String,
+        keep_going: bool,
+        backend: Backend,
+        trace: bool,
+        gc_stress: bool,
+    },
+    Repl,
+    Tokens(String),
+    Ast(String),
+    Disasm(String),
+    Compile {
+        file: String,
+        output: String,
+    },
+    Check(String),
+    Test {
+        dir: String,
+        backend: Backend,
+        gc_stress: bool,
+    },
+    Help,
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    args.iter().for_each(|arg| println!("Argument: {}", arg));
-    // Rust includes the path of the exe as the default 0th arg.
-    if args.len() == 1 || args[1] == "/?" || args[1] == "--help" || args[1] == "-?" {
-        print_help();
-    } else if args.len() == 2 && args[1] == "prompt" {
-        run_prompt(ReplMode::Standard);
-    } else if args.len() == 2 && args[1] == "debug-prompt" {
-        run_prompt(ReplMode::Debug);
-    } else if args.len() == 3 && args[1] == "rlox" {
-        run_file(&args[2]);
-    } else {
-        println!("Can't understand your args");
-        print_help();
+    // Rust includes the path of the exe as the 0th arg.
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = match parse_args(&args) {
+        Ok(command) => thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || execute(command))
+            .expect("Could not start the interpreter thread.")
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            EXIT_USAGE
+        }
+    };
+    let _ = io::stdout().flush();
+    process::exit(code);
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    match args[..] {
+        [] | ["repl"] => Ok(Command::Repl),
+        ["run", ref options @ .., file] => parse_run(options, file),
+        ["tokens", file] => Ok(Command::Tokens(file.to_string())),
+        ["ast", file] => Ok(Command::Ast(file.to_string())),
+        ["disasm", file] => Ok(Command::Disasm(file.to_string())),
+        ["compile", file] => Ok(Command::Compile {
+            file: file.to_string(),
+            output: Path::new(file).with_extension("loxc").display().to_string(),
+        }),
+        ["compile", file, "-o", output] | ["compile", "-o", output, file] => Ok(Command::Compile {
+            file: file.to_string(),
+            output: output.to_string(),
+        }),
+        ["check", file] => Ok(Command::Check(file.to_string())),
+        ["test", ref options @ .., dir] => parse_test(options, dir),
+        ["help" | "--help" | "-h" | "-?" | "/?"] => Ok(Command::Help),
+        ["tokens" | "ast" | "disasm" | "check", ..] => Err(format!("'{}' takes exactly one file.", args[0])),
+        ["test", ..] => Err("'test' takes exactly one directory.".to_string()),
+        ["compile", ..] => Err("'compile' takes one file and optionally -o and an output file.".to_string()),
+        ["repl", ..] => Err("'repl' doesn't take any arguments.".to_string()),
+        [command, ..] => Err(format!("Can't understand the command '{}'.", command)),
+    }
+}
+
+fn parse_run(options: &[&str], file: &str) -> Result<Command, String> {
+    let mut keep_going = false;
+    let mut backend = Backend::TreeWalker;
+    let mut trace = false;
+    let mut gc_stress = false;
+    for option in options {
+        match *option {
+            "--keep-going" => keep_going = true,
+            "--vm" => backend = Backend::Vm,
+            "--trace" => {
+                backend = Backend::Vm;
+                trace = true;
+            }
+            "--gc-stress" => gc_stress = true,
+            _ => {
+                return Err(format!(
+                    "'run' takes exactly one file and doesn't know '{}'.",
+                    option
+                ))
+            }
+        }
     }
+    // Compiled programs can only run on the virtual machine.
+    if file.ends_with(".loxc") {
+        backend = Backend::Vm;
+    }
+    if keep_going && backend == Backend::Vm {
+        return Err("--keep-going can't be used with --vm, --trace or a .loxc file.".to_string());
+    }
+    Ok(Command::Run {
+        file: file.to_string(),
+        keep_going,
+        backend,
+        trace,
+        gc_stress,
+    })
 }
 
-fn print_help() {
-    println!("Execute script          : rlox [script]");
-    println!("Start interactive prompt: prompt");
-    println!("Start interactive language dev prompt: debug-prompt");
+fn parse_test(options: &[&str], dir: &str) -> Result<Command, String> {
+    let mut backend = Backend::TreeWalker;
+    let mut gc_stress = false;
+    for option in options {
+        match *option {
+            "--vm" => backend = Backend::Vm,
+            "--gc-stress" => gc_stress = true,
+            _ => {
+                return Err(format!(
+                    "'test' takes exactly one directory and doesn't know '{}'.",
+                    option
+                ))
+            }
+        }
+    }
+    Ok(Command::Test {
+        dir: dir.to_string(),
+        backend,
+        gc_stress,
+    })
 }
 
-fn run_file(filepath: &str) {
-    let raw_source = load_source(filepath);
-    run(raw_source);
+fn execute(command: Command) -> i32 {
+    match command {
+        Command::Help => {
+            println!("{}", USAGE);
+            0
+        }
+        Command::Repl => {
+            run_prompt();
+            0
+        }
+        Command::Run {
+            file,
+            keep_going,
+            backend,
+            trace,
+            gc_stress,
+        } => run_script(&file, keep_going, backend, trace, gc_config(gc_stress)),
+        Command::Tokens(file) => with_source(&file, print_tokens),
+        Command::Ast(file) => with_source(&file, print_ast),
+        Command::Disasm(file) => with_source(&file, print_bytecode),
+        Command::Compile { file, output } => with_source(&file, |source, filename| {
+            compile_to_file(source, filename, &output)
+        }),
+        Command::Check(file) => with_source(&file, check),
+        Command::Test {
+            dir,
+            backend,
+            gc_stress,
+        } => run_tests(&dir, backend, gc_config(gc_stress)),
+    }
 }
 
-fn run_prompt(mode: ReplMode) {
+/// Reads the file, or stdin for `-`, and hands the source and its name to `f`.
+fn with_source<F: FnOnce(&str, &str) -> i32>(file: &str, f: F) -> i32 {
+    let (source, filename) = match file {
+        "-" => {
+            let mut source = String::new();
+            (io::stdin().read_to_string(&mut source).map(|_| source), "<stdin>")
+        }
+        _ => (fs::read_to_string(file), file),
+    };
+    match source {
+        Ok(source) => f(&source, filename),
+        Err(err) => {
+            eprintln!("Could not read {}: {}", filename, err);
+            EXIT_NO_INPUT
+        }
+    }
+}
+
+/// Colour is used when writing errors to a terminal, unless the NO_COLOR convention asks us not to.
+fn use_color(terminal: bool) -> bool {
+    terminal && env::var_os("NO_COLOR").is_none()
+}
+
+/// Prints the errors, showing the code they point at when the source is given, and returns the
+/// exit code for them.
+fn report(errors: &[LoxError], source: Option<&str>) -> i32 {
+    let color = use_color(io::stderr().is_terminal());
+    for err in errors {
+        eprint!("{}", Diagnostic::from_error(err).render(source, color));
+    }
+    match errors
+        .iter()
+        .any(|err| matches!(err, LoxError::SyntaxError(_, _) | LoxError::LoadError(_, _)))
+    {
+        true => EXIT_COMPILE_ERROR,
+        false => EXIT_RUNTIME_ERROR,
+    }
+}
+
+fn run_prompt() {
+    let mut repl = Repl::new(InterpreterState::<std::io::Stdout>::default());
+    repl.set_color(use_color(io::stderr().is_terminal()));
     let stdin = io::stdin();
-    for line in stdin.lock().lines() {
-        let unwrapped_line = match line {
-            Ok(x) => x,
-            Err(_) => break,
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", repl.prompt());
+        let _ = io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(x)) => x,
+            _ => break,
         };
-        if unwrapped_line.is_empty() {
+        if repl.handle_line(&line) == ReplStatus::Quit {
             break;
-        } else {
-            match mode {
-                ReplMode::Standard => run(unwrapped_line),
-                ReplMode::Debug => run_debug(unwrapped_line),
-            }
         }
     }
 }
 
-fn run(raw_source: String) {
-    let mut source = SourceCode::new(&raw_source, "repl.lox".to_string());
-    let tokens = source.scan_tokens();
-    let mut parser = Parser::new(tokens);
-    match parser.parse() {
-        Ok(ast) => {
-            let state = &mut InterpreterState::<std::io::Stdout>::default();
-            let interpreter = Interpreter::new(ast);
-            interpreter.interpret(state);
+fn gc_config(gc_stress: bool) -> GcConfig {
+    match gc_stress {
+        true => GcConfig::stress(),
+        false => GcConfig::default(),
+    }
+}
+
+fn run_script(file: &str, keep_going: bool, backend: Backend, trace: bool, gc: GcConfig) -> i32 {
+    if file.ends_with(".loxc") {
+        return run_compiled(file, trace, gc);
+    }
+    match (backend, keep_going) {
+        (Backend::Vm, _) => with_source(file, |source, filename| run_on_vm(source, filename, trace, gc)),
+        (Backend::TreeWalker, false) => with_source(file, |source, filename| {
+            run_with_policy(source, filename, ErrorPolicy::Abort, gc)
+        }),
+        (Backend::TreeWalker, true) => with_source(file, |source, filename| {
+            run_with_policy(source, filename, ErrorPolicy::Continue, gc)
+        }),
+    }
+}
+
+fn run_with_policy(source: &str, filename: &str, error_policy: ErrorPolicy, gc: GcConfig) -> i32 {
+    let mut lox = Lox::new();
+    lox.set_error_policy(error_policy);
+    lox.set_gc_config(gc);
+    match lox.run_source(source, filename) {
+        Ok(_) => 0,
+        Err(errors) => report(&errors, Some(source)),
+    }
+}
+
+fn run_compiled(path: &str, trace: bool, gc: GcConfig) -> i32 {
+    let script = match loxc::read_file(path) {
+        Ok(script) => script,
+        Err(LoxError::Syscall(_, err)) => {
+            eprintln!("Could not read {}: {}", path, err);
+            return EXIT_NO_INPUT;
         }
-        Err(err) => println!("Lox error:\n{}", err),
+        Err(err) => return report(&[err], None),
+    };
+    let mut vm = Vm::new(io::stdout());
+    vm.set_trace(trace);
+    vm.heap_mut().set_config(gc);
+    match vm.run(Rc::new(script)) {
+        Ok(()) => 0,
+        Err(err) => report(&[err], None),
     }
 }
 
-fn run_debug(raw_source: String) {
-    let mut source = SourceCode::new(&raw_source, "repl.lox".to_string());
-    let tokens = source.scan_tokens();
+fn run_on_vm(source: &str, filename: &str, trace: bool, gc: GcConfig) -> i32 {
+    let mut vm = Vm::new(io::stdout());
+    vm.set_trace(trace);
+    vm.heap_mut().set_config(gc);
+    match vm.run_source(source, filename) {
+        Ok(()) => 0,
+        Err(errors) => report(&errors, Some(source)),
+    }
+}
 
+fn print_tokens(source: &str, filename: &str) -> i32 {
+    let tokens = SourceCode::new(source, filename.to_string()).scan_tokens();
+    let mut errors = Vec::new();
     for token in &tokens {
-        println!("{:?}", token);
+        println!("{:<8} {:?}", position(token), token.token_type);
+        if let TokenType::Error(message) = &token.token_type {
+            errors.push(LoxError::SyntaxError(token.location.clone(), message.clone()));
+        }
+    }
+    match errors.is_empty() {
+        true => 0,
+        false => report(&errors, Some(source)),
+    }
+}
+
+fn position(token: &Token) -> String {
+    match token.location.span() {
+        Some(span) => format!("{}:{}", span.line, span.column),
+        None => String::new(),
+    }
+}
+
+fn parse(source: &str, filename: &str) -> Result<Vec<Stmt>, Vec<LoxError>> {
+    let tokens = SourceCode::new(source, filename.to_string()).scan_tokens();
+    let (statements, errors) = Parser::new(tokens).parse_recovering();
+    match errors.is_empty() {
+        true => Ok(statements),
+        false => Err(errors),
+    }
+}
+
+fn print_ast(source: &str, filename: &str) -> i32 {
+    match parse(source, filename) {
+        Ok(statements) => {
+            println!("{}", parenthesize_statements(&statements));
+            0
+        }
+        Err(errors) => report(&errors, Some(source)),
+    }
+}
+
+/// Parses, resolves and compiles the source to bytecode.
+fn compile(source: &str, filename: &str) -> Result<Function, Vec<LoxError>> {
+    let statements = parse(source, filename)?;
+    let errors = Resolver::new().resolve(&statements);
+    match errors.is_empty() {
+        true => compiler::compile(&statements, filename),
+        false => Err(errors),
+    }
+}
+
+fn compile_to_file(source: &str, filename: &str, output: &str) -> i32 {
+    let script = match compile(source, filename) {
+        Ok(script) => script,
+        Err(errors) => return report(&errors, Some(source)),
+    };
+    match loxc::write_file(&script, output) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("Could not write {}: {}", output, err.message());
+            EXIT_CANT_CREATE
+        }
     }
+}
 
-    let mut parser = Parser::new(tokens);
-    match parser.parse() {
-        Ok(ast) => {
-            println!("{}", parenthesize_statements(&ast));
-            let state = &mut InterpreterState::<std::io::Stdout>::default();
-            let interpreter = Interpreter::new(ast);
-            interpreter.interpret(state);
+fn print_bytecode(source: &str, filename: &str) -> i32 {
+    match compile(source, filename) {
+        Ok(function) => {
+            print!("{}", disassemble(&function, Some(source)));
+            0
         }
-        Err(err) => println!("Lox error:\n{}", err),
+        Err(errors) => report(&errors, Some(source)),
+    }
+}
+
+fn check(source: &str, filename: &str) -> i32 {
+    let errors = match parse(source, filename) {
+        Ok(statements) => Resolver::new().resolve(&statements),
+        Err(errors) => errors,
+    };
+    match errors.is_empty() {
+        true => 0,
+        false => report(&errors, Some(source)),
+    }
+}
+
+fn run_tests(dir: &str, backend: Backend, gc: GcConfig) -> i32 {
+    let results = match conformance::run_dir(Path::new(dir), backend, gc) {
+        Ok(results) => results,
+        Err(err) => {
+            eprintln!("Could not read the tests in {}: {}", dir, err);
+            return EXIT_NO_INPUT;
+        }
+    };
+    for result in &results {
+        println!("{}", result);
+    }
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    println!("\n{} passed, {} failed", results.len() - failed, failed);
+    match failed {
+        0 => 0,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[rstest]
+    #[case(&[], "repl")]
+    #[case(&["run", "a.lox"], "run a.lox")]
+    #[case(&["run", "--keep-going", "a.lox"], "run --keep-going a.lox")]
+    #[case(&["run", "--vm", "a.lox"], "run --vm a.lox")]
+    #[case(&["run", "--trace", "a.lox"], "run --trace a.lox")]
+    #[case(&["run", "--gc-stress", "a.lox"], "run --gc-stress a.lox")]
+    #[case(&["run", "--vm", "--gc-stress", "a.lox"], "run --vm --gc-stress a.lox")]
+    #[case(&["tokens", "-"], "tokens -")]
+    #[case(&["ast", "a.lox"], "ast a.lox")]
+    #[case(&["disasm", "a.lox"], "disasm a.lox")]
+    #[case(&["compile", "a.lox"], "compile a.lox -o a.loxc")]
+    #[case(&["compile", "a.lox", "-o", "b.loxc"], "compile a.lox -o b.loxc")]
+    #[case(&["run", "a.loxc"], "run --vm a.loxc")]
+    #[case(&["check", "a.lox"], "check a.lox")]
+    #[case(&["test", "data"], "test data")]
+    #[case(&["test", "--vm", "data"], "test --vm data")]
+    #[case(&["test", "--gc-stress", "--vm", "data"], "test --vm --gc-stress data")]
+    #[case(&["--help"], "help")]
+    fn test_parse_args(#[case] arguments: &[&str], #[case] expected: &str) {
+        let command = match parse_args(&args(arguments)).unwrap() {
+            Command::Repl => "repl".to_string(),
+            Command::Run {
+                file,
+                keep_going,
+                backend,
+                trace,
+                gc_stress,
+            } => {
+                let mode = match (keep_going, backend, trace) {
+                    (true, _, _) => " --keep-going",
+                    (false, _, true) => " --trace",
+                    (false, Backend::Vm, false) => " --vm",
+                    (false, Backend::TreeWalker, false) => "",
+                };
+                let gc = if gc_stress { " --gc-stress" } else { "" };
+                format!("run{}{} {}", mode, gc, file)
+            }
+            Command::Tokens(file) => format!("tokens {}", file),
+            Command::Ast(file) => format!("ast {}", file),
+            Command::Disasm(file) => format!("disasm {}", file),
+            Command::Compile { file, output } => format!("compile {} -o {}", file, output),
+            Command::Check(file) => format!("check {}", file),
+            Command::Test {
+                dir,
+                backend,
+                gc_stress,
+            } => {
+                let vm = if backend == Backend::Vm { " --vm" } else { "" };
+                let gc = if gc_stress { " --gc-stress" } else { "" };
+                format!("test{}{} {}", vm, gc, dir)
+            }
+            Command::Help => "help".to_string(),
+        };
+        assert_eq!(command, expected);
+    }
+
+    #[rstest]
+    #[case(&["rlox", "a.lox"])]
+    #[case(&["run"])]
+    #[case(&["run", "a.lox", "b.lox"])]
+    #[case(&["run", "--keep-going", "--vm", "a.lox"])]
+    #[case(&["repl", "--debug"])]
+    #[case(&["compile", "a.lox", "-o"])]
+    #[case(&["run", "--keep-going", "a.loxc"])]
+    #[case(&["test", "--trace", "data"])]
+    #[case(&["test"])]
+    fn test_parse_args_rejects_bad_usage(#[case] arguments: &[&str]) {
+        assert!(parse_args(&args(arguments)).is_err());
     }
 }
@@ -0,0 +1,26 @@
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::environment::Environment;
+use crate::value::{NativeFunction, Value};
+
+/// An environment whose globals hold the built-in native functions.
+pub fn global_environment() -> Environment {
+    let mut environment = Environment::new();
+    let clock = NativeFunction::new("clock", 0, clock);
+    environment.define_global("clock".to_string(), Value::NativeFunction(Rc::new(clock)));
+    environment
+}
+
+/// Seconds since the Unix epoch, for timing scripts.
+fn clock(_arguments: &[Value]) -> Result<Value, String> {
+    seconds_since_epoch().map(Value::Number)
+}
+
+/// Seconds since the Unix epoch, shared by every backend's `clock()`.
+pub fn seconds_since_epoch() -> Result<f64, String> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .map_err(|err| format!("Could not read the clock: {}", err))
+}
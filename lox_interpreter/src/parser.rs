@@ -1,3 +1,6 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
 use crate::core::errors::LoxError;
 use crate::core::location::Location;
 use crate::tokens::Token;
@@ -6,7 +9,7 @@ use crate::tokens::TokenType;
 #[derive(PartialEq, Debug)]
 pub enum Literal {
     Nil,
-    Number(f32),
+    Number(f64),
     String(String),
     True,
     False,
@@ -14,14 +17,57 @@ pub enum Literal {
 
 #[derive(PartialEq, Debug)]
 pub enum Expr {
-    Assign(Token, Box<Expr>),
+    Assign(Token, Box<Expr>, ScopeDepth),
     Binary(Box<Expr>, Token, Box<Expr>),
+    Call(Box<Expr>, Token, Vec<Expr>),
+    Get(Box<Expr>, Token),
     Grouping(Box<Expr>),
     Literal(Location, Literal),
     Logical(Box<Expr>, Token, Box<Expr>),
+    Set(Box<Expr>, Token, Box<Expr>),
+    Super(Token, Token, ScopeDepth),
     Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    This(Token, ScopeDepth),
     Unary(Token, Box<Expr>),
-    Variable(Token),
+    Variable(Token, ScopeDepth),
+}
+
+impl Expr {
+    /// The location of the whole expression, from its first token to its last. A grouping covers
+    /// only the expression inside the parentheses.
+    pub fn location(&self) -> Location {
+        match self {
+            Expr::Assign(name, value, _) => name.location.to(&value.location()),
+            Expr::Binary(left, _, right) | Expr::Logical(left, _, right) => {
+                left.location().to(&right.location())
+            }
+            Expr::Call(callee, paren, _) => callee.location().to(&paren.location),
+            Expr::Get(object, name) => object.location().to(&name.location),
+            Expr::Grouping(expr) => expr.location(),
+            Expr::Literal(location, _) => location.clone(),
+            Expr::Set(object, _, value) => object.location().to(&value.location()),
+            Expr::Super(keyword, method, _) => keyword.location.to(&method.location),
+            Expr::Ternary(condition, _, else_branch) => condition.location().to(&else_branch.location()),
+            Expr::This(keyword, _) => keyword.location.clone(),
+            Expr::Unary(operator, expr) => operator.location.to(&expr.location()),
+            Expr::Variable(name, _) => name.location.clone(),
+        }
+    }
+}
+
+/// How many scopes out from the innermost one a variable was declared. This is filled in by the
+/// resolver before the program runs and `None` means the variable is a global.
+#[derive(PartialEq, Debug, Default)]
+pub struct ScopeDepth(Cell<Option<usize>>);
+
+impl ScopeDepth {
+    pub fn get(&self) -> Option<usize> {
+        self.0.get()
+    }
+
+    pub fn set(&self, depth: usize) {
+        self.0.set(Some(depth));
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -31,7 +77,55 @@ pub enum Stmt {
     Var(Token, Expr),
     Block(Vec<Stmt>),
     If(Expr, Box<Stmt>, Option<Box<Stmt>>),
-    While(Expr, Box<Stmt>),
+    /// The condition, the body and, for desugared `for` loops, the increment. The increment is kept
+    /// apart from the body so that `continue` still runs it.
+    While(Expr, Box<Stmt>, Option<Expr>),
+    Function(Rc<FunctionDecl>),
+    Return(Token, Option<Expr>),
+    Break(Token),
+    Continue(Token),
+    Class(Token, Option<Expr>, Vec<Rc<FunctionDecl>>),
+}
+
+impl Stmt {
+    /// The location of the statement, built from the tokens and expressions it holds. Keywords
+    /// and punctuation that aren't kept in the AST, such as `print` or a block's braces, aren't
+    /// covered.
+    pub fn location(&self) -> Location {
+        match self {
+            Stmt::Expression(expr) | Stmt::Print(expr) => expr.location(),
+            Stmt::Var(name, initializer) => name.location.to(&initializer.location()),
+            Stmt::Block(statements) => match (statements.first(), statements.last()) {
+                (Some(first), Some(last)) => first.location().to(&last.location()),
+                _ => Location::Unknown,
+            },
+            Stmt::If(condition, then_branch, else_branch) => {
+                let last = else_branch.as_ref().unwrap_or(then_branch);
+                condition.location().to(&last.location())
+            }
+            Stmt::While(condition, body, _) => condition.location().to(&body.location()),
+            Stmt::Function(declaration) => match declaration.body.last() {
+                Some(last) => declaration.name.location.to(&last.location()),
+                None => declaration.name.location.clone(),
+            },
+            Stmt::Return(keyword, value) => match value {
+                Some(value) => keyword.location.to(&value.location()),
+                None => keyword.location.clone(),
+            },
+            Stmt::Break(keyword) | Stmt::Continue(keyword) => keyword.location.clone(),
+            Stmt::Class(name, _, methods) => match methods.last().and_then(|method| method.body.last()) {
+                Some(last) => name.location.to(&last.location()),
+                None => name.location.clone(),
+            },
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct FunctionDecl {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Stmt>,
 }
 
 #[derive(PartialEq)]
@@ -39,41 +133,85 @@ pub struct Ast {
     pub root_expr: Expr,
 }
 
+const MAX_ARGUMENTS: usize = 255;
+
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    // How many loops enclose the statement being parsed, within the innermost function.
+    loop_depth: usize,
+    // How many blocks enclose the token being parsed, so recovery knows a `}` closes one of them.
+    block_depth: usize,
+    errors: Vec<LoxError>,
 }
 
 pub trait ParseResult {
     fn must(self) -> Vec<Stmt>;
 }
 
-impl ParseResult for Result<Vec<Stmt>, LoxError> {
+impl ParseResult for Result<Vec<Stmt>, Vec<LoxError>> {
     fn must(self) -> Vec<Stmt> {
         match self {
             Ok(statements) => statements,
-            Err(err) => panic!("Parsing failed and it must succeed. Error: {}", err),
+            Err(errors) => panic!("Parsing failed and it must succeed. Errors: {:?}", errors),
         }
     }
 }
 
 impl Parser {
+    /// Tokens the scanner couldn't make sense of are taken out and reported as syntax errors.
     pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, current: 0 }
+        let mut errors = Vec::new();
+        let tokens = tokens
+            .into_iter()
+            .filter_map(|token| match token.token_type {
+                TokenType::Error(message) => {
+                    errors.push(LoxError::SyntaxError(token.location, message));
+                    None
+                }
+                _ => Some(token),
+            })
+            .collect();
+        Parser {
+            tokens,
+            current: 0,
+            loop_depth: 0,
+            block_depth: 0,
+            errors,
+        }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Stmt>, LoxError> {
-        let mut statements: Vec<Stmt> = Vec::new();
-        while !self.is_at_end() {
-            let stmt = self.declaration()?;
-            statements.push(stmt);
+    /// Parses every statement, returning all the syntax errors found if there were any.
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<LoxError>> {
+        let (statements, errors) = self.parse_recovering();
+        if !errors.is_empty() {
+            return Err(errors);
         }
         match statements.len() {
-            0 => Err(LoxError::Critical("No tokens parsed.".to_string())),
+            0 => Err(vec![LoxError::Critical("No tokens parsed.".to_string())]),
             _ => Ok(statements),
         }
     }
 
+    /// Parses as much as it can. After a syntax error the parser skips to the next statement and
+    /// carries on, so the statements it could make sense of come back with every error found.
+    pub fn parse_recovering(&mut self) -> (Vec<Stmt>, Vec<LoxError>) {
+        let mut statements: Vec<Stmt> = Vec::new();
+        while !self.is_at_end() {
+            if let Some(stmt) = self.declaration() {
+                statements.push(stmt);
+            }
+        }
+        // Errors from the scanner were collected first, so put them all back in source order.
+        let mut errors = std::mem::take(&mut self.errors);
+        errors.sort_by_key(|err| {
+            err.location()
+                .and_then(|location| location.span())
+                .map(|span| span.start)
+        });
+        (statements, errors)
+    }
+
     fn previous(&self) -> &Token {
         &self.tokens[self.current - 1]
     }
@@ -113,17 +251,86 @@ impl Parser {
         false
     }
 
-    fn declaration(&mut self) -> Result<Stmt, LoxError> {
-        if self.match_token_type(&[TokenType::Var]) {
-            match self.var_declaration() {
-                Ok(x) => return Ok(x),
-                Err(err) => {
-                    self.synchronize();
-                    return Err(err);
+    /// Parses a declaration, or records the error and skips past it if it is malformed.
+    fn declaration(&mut self) -> Option<Stmt> {
+        let result = if self.match_token_type(&[TokenType::Class]) {
+            self.class_declaration()
+        } else if self.match_token_type(&[TokenType::Fun]) {
+            self.function("function").map(Stmt::Function)
+        } else if self.match_token_type(&[TokenType::Var]) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        };
+        match result {
+            Ok(stmt) => Some(stmt),
+            Err(err) => {
+                self.errors.push(err);
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt, LoxError> {
+        let name = self.consume_identifier("Expect class name.")?.clone();
+
+        let mut superclass: Option<Expr> = None;
+        if self.match_token_type(&[TokenType::Less]) {
+            let superclass_name = self.consume_identifier("Expect superclass name.")?.clone();
+            if superclass_name.token_type == name.token_type {
+                return Err(LoxError::SyntaxError(
+                    superclass_name.location,
+                    "A class can't inherit from itself.".to_string(),
+                ));
+            }
+            superclass = Some(Expr::Variable(superclass_name, ScopeDepth::default()));
+        }
+
+        self.consume(&TokenType::LeftBrace, "Expect '{' before class body.")?;
+        let mut methods: Vec<Rc<FunctionDecl>> = Vec::new();
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+        }
+        self.consume(&TokenType::RightBrace, "Expect '}' after class body.")?;
+        Ok(Stmt::Class(name, superclass, methods))
+    }
+
+    fn function(&mut self, kind: &str) -> Result<Rc<FunctionDecl>, LoxError> {
+        let name = self
+            .consume_identifier(&format!("Expect {} name.", kind))?
+            .clone();
+        self.consume(&TokenType::LeftParen, &format!("Expect '(' after {} name.", kind))?;
+        let mut params: Vec<Token> = Vec::new();
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                if params.len() >= MAX_ARGUMENTS {
+                    let location = self.tokens[self.current].location.clone();
+                    return Err(LoxError::SyntaxError(
+                        location,
+                        format!("Can't have more than {} parameters.", MAX_ARGUMENTS),
+                    ));
+                }
+                params.push(self.consume_identifier("Expect parameter name.")?.clone());
+                if !self.match_token_type(&[TokenType::Comma]) {
+                    break;
                 }
             }
         }
-        self.statement()
+        self.consume(&TokenType::RightParen, "Expect ')' after parameters.")?;
+        self.consume(
+            &TokenType::LeftBrace,
+            &format!("Expect '{{' before {} body.", kind),
+        )?;
+        // A loop outside the function doesn't let `break` or `continue` be used inside it.
+        let enclosing_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let body = self.block();
+        self.loop_depth = enclosing_loop_depth;
+        Ok(Rc::new(FunctionDecl {
+            name,
+            params,
+            body: body?,
+        }))
     }
 
     fn var_declaration(&mut self) -> Result<Stmt, LoxError> {
@@ -158,6 +365,10 @@ impl Parser {
             self.while_statement()
         } else if self.match_token_type(&[TokenType::For]) {
             self.for_statement()
+        } else if self.match_token_type(&[TokenType::Return]) {
+            self.return_statement()
+        } else if self.match_token_type(&[TokenType::Break, TokenType::Continue]) {
+            self.loop_control_statement()
         } else if self.match_token_type(&[TokenType::LeftBrace]) {
             let block = self.block()?;
             Ok(Stmt::Block(block))
@@ -166,6 +377,42 @@ impl Parser {
         }
     }
 
+    fn return_statement(&mut self) -> Result<Stmt, LoxError> {
+        let keyword = self.previous().clone();
+        let value = match self.check(&TokenType::SemiColon) {
+            true => None,
+            false => Some(self.expression()?),
+        };
+        self.consume(&TokenType::SemiColon, "Expect ';' after return value.")?;
+        Ok(Stmt::Return(keyword, value))
+    }
+
+    fn loop_control_statement(&mut self) -> Result<Stmt, LoxError> {
+        let keyword = self.previous().clone();
+        let name = match keyword.token_type {
+            TokenType::Break => "break",
+            _ => "continue",
+        };
+        if self.loop_depth == 0 {
+            return Err(LoxError::SyntaxError(
+                keyword.location,
+                format!("Can't use '{}' outside of a loop.", name),
+            ));
+        }
+        self.consume(&TokenType::SemiColon, &format!("Expect ';' after '{}'.", name))?;
+        match keyword.token_type {
+            TokenType::Break => Ok(Stmt::Break(keyword)),
+            _ => Ok(Stmt::Continue(keyword)),
+        }
+    }
+
+    fn loop_body(&mut self) -> Result<Stmt, LoxError> {
+        self.loop_depth += 1;
+        let body = self.statement();
+        self.loop_depth -= 1;
+        body
+    }
+
     fn for_statement(&mut self) -> Result<Stmt, LoxError> {
         let left_paren = self
             .consume(&TokenType::LeftParen, "Expect '(' before for statement.")?
@@ -180,32 +427,25 @@ impl Parser {
         if !self.check(&TokenType::SemiColon) {
             condition = self.expression()?;
         }
-        let _ = self.consume(&TokenType::SemiColon, "Expect ';' after loop condition.");
+        self.consume(&TokenType::SemiColon, "Expect ';' after loop condition.")?;
 
         let mut increment: Option<Expr> = None;
         if !self.check(&TokenType::RightParen) {
             increment = Some(self.expression()?);
         }
-        let _ = self.consume(&TokenType::RightParen, "Expect ')' after for clauses.");
-
-        let mut body = self.statement()?;
-
-        if let Some(increment) = increment {
-            body = Stmt::Block(vec![body, Stmt::Expression(increment)]);
-        }
-
-        body = Stmt::While(condition, Box::new(body));
-        body = Stmt::Block(vec![initializer, body]);
+        self.consume(&TokenType::RightParen, "Expect ')' after for clauses.")?;
 
-        Ok(body)
+        let body = self.loop_body()?;
+        let while_loop = Stmt::While(condition, Box::new(body), increment);
+        Ok(Stmt::Block(vec![initializer, while_loop]))
     }
 
     fn while_statement(&mut self) -> Result<Stmt, LoxError> {
-        let _ = self.consume(&TokenType::LeftParen, "Expect '(' before while statement.");
+        self.consume(&TokenType::LeftParen, "Expect '(' before while statement.")?;
         let condition = self.expression()?;
-        let _ = self.consume(&TokenType::RightParen, "Expect ')' after while statement.");
-        let body = self.statement()?;
-        Ok(Stmt::While(condition, Box::new(body)))
+        self.consume(&TokenType::RightParen, "Expect ')' after while statement.")?;
+        let body = self.loop_body()?;
+        Ok(Stmt::While(condition, Box::new(body), None))
     }
 
     fn if_statement(&mut self) -> Result<Stmt, LoxError> {
@@ -226,10 +466,13 @@ impl Parser {
 
     fn block(&mut self) -> Result<Vec<Stmt>, LoxError> {
         let mut statements: Vec<Stmt> = Vec::new();
+        self.block_depth += 1;
         while !self.is_at_end() && !self.check(&TokenType::RightBrace) {
-            let stmt = self.declaration()?;
-            statements.push(stmt);
+            if let Some(stmt) = self.declaration() {
+                statements.push(stmt);
+            }
         }
+        self.block_depth -= 1;
 
         let _ = self.consume(&TokenType::RightBrace, "Expect '}' after block.")?;
 
@@ -253,7 +496,7 @@ impl Parser {
     }
 
     fn assignment(&mut self) -> Result<Expr, LoxError> {
-        let expr = self.or()?;
+        let expr = self.ternary()?;
 
         // let expr = self.equality()?;
         if self.match_token_type(&[TokenType::Equal]) {
@@ -261,8 +504,9 @@ impl Parser {
             let value = self.assignment()?;
 
             return match expr {
-                Expr::Variable(token) => Ok(Expr::Assign(token, Box::new(value))),
-                _ => Err(LoxError::RuntimeError(
+                Expr::Variable(token, _) => Ok(Expr::Assign(token, Box::new(value), ScopeDepth::default())),
+                Expr::Get(object, name) => Ok(Expr::Set(object, name, Box::new(value))),
+                _ => Err(LoxError::SyntaxError(
                     location,
                     "Invalid assignment target".to_string(),
                 )),
@@ -271,6 +515,26 @@ impl Parser {
         Ok(expr)
     }
 
+    /// `condition ? then : else`, which binds looser than `or` and is right associative so that
+    /// `a ? b : c ? d : e` groups as `a ? b : (c ? d : e)`.
+    fn ternary(&mut self) -> Result<Expr, LoxError> {
+        let expr = self.or()?;
+        if self.match_token_type(&[TokenType::Question]) {
+            let then_branch = self.expression()?;
+            self.consume(
+                &TokenType::Colon,
+                "Expect ':' after then branch of conditional expression.",
+            )?;
+            let else_branch = self.ternary()?;
+            return Ok(Expr::Ternary(
+                Box::new(expr),
+                Box::new(then_branch),
+                Box::new(else_branch),
+            ));
+        }
+        Ok(expr)
+    }
+
     fn or(&mut self) -> Result<Expr, LoxError> {
         let mut expr = self.and()?;
 
@@ -346,7 +610,47 @@ impl Parser {
             };
         }
 
-        self.primary()
+        self.call()
+    }
+
+    fn call(&mut self) -> Result<Expr, LoxError> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.match_token_type(&[TokenType::LeftParen]) {
+                expr = self.finish_call(expr)?;
+            } else if self.match_token_type(&[TokenType::Dot]) {
+                let name = self
+                    .consume_identifier("Expect property name after '.'.")?
+                    .clone();
+                expr = Expr::Get(Box::new(expr), name);
+            } else {
+                break;
+            }
+        }
+        Ok(expr)
+    }
+
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, LoxError> {
+        let mut arguments: Vec<Expr> = Vec::new();
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                if arguments.len() >= MAX_ARGUMENTS {
+                    let location = self.tokens[self.current].location.clone();
+                    return Err(LoxError::SyntaxError(
+                        location,
+                        format!("Can't have more than {} arguments.", MAX_ARGUMENTS),
+                    ));
+                }
+                arguments.push(self.expression()?);
+                if !self.match_token_type(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        let paren = self
+            .consume(&TokenType::RightParen, "Expect ')' after arguments.")?
+            .clone();
+        Ok(Expr::Call(Box::new(callee), paren, arguments))
     }
 
     fn primary(&mut self) -> Result<Expr, LoxError> {
@@ -387,10 +691,21 @@ impl Parser {
                 self.consume(&TokenType::RightParen, "Expect ')' after expression. After the expression finishes parsing the next token type must be a RightParen.")?;
                 expr = Some(Expr::Grouping(Box::new(expr_result)));
             }
+            TokenType::Super => {
+                self.current += 1;
+                let keyword = token.clone();
+                self.consume(&TokenType::Dot, "Expect '.' after 'super'.")?;
+                let method = self.consume_identifier("Expect superclass method name.")?.clone();
+                expr = Some(Expr::Super(keyword, method, ScopeDepth::default()))
+            }
+            TokenType::This => {
+                self.current += 1;
+                expr = Some(Expr::This(token.clone(), ScopeDepth::default()))
+            }
             TokenType::Identifier(_) => {
                 self.current += 1;
                 //expr = Some(Expr::Variable(self.previous().clone()))
-                expr = Some(Expr::Variable(token.clone()))
+                expr = Some(Expr::Variable(token.clone(), ScopeDepth::default()))
             }
             _ => {}
         }
@@ -399,7 +714,7 @@ impl Parser {
             None => {
                 // Why do I have to get the token from self and can't use token.location.clone()
                 let t = self.tokens.get(self.current).unwrap();
-                Err(LoxError::RuntimeError(
+                Err(LoxError::SyntaxError(
                     t.location.clone(),
                     "Expected expression and found None.".to_string(),
                 ))
@@ -430,13 +745,34 @@ impl Parser {
         Err(LoxError::SyntaxError(unexpected_token.location, msg))
     }
 
+    /// Skips tokens until the start of the next statement. A `}` closing the enclosing block is
+    /// left for the block to consume, while blocks opened in the skipped tokens are skipped whole.
     fn synchronize(&mut self) {
+        if self.closes_enclosing_block(0) {
+            return;
+        }
+        let mut nesting = self.skip_brace(0);
         self.advance();
 
         while !self.is_at_end() {
-            if self.previous().token_type == TokenType::SemiColon {
+            // A skipped block ends the statement unless a semicolon follows, as in `var a = {};`.
+            let previous = &self.previous().token_type;
+            let ends_statement = match previous {
+                TokenType::SemiColon => true,
+                TokenType::RightBrace => !self.check(&TokenType::SemiColon),
+                _ => false,
+            };
+            if nesting == 0 && ends_statement {
                 return;
             }
+            if self.closes_enclosing_block(nesting) {
+                return;
+            }
+            if nesting > 0 {
+                nesting = self.skip_brace(nesting);
+                self.advance();
+                continue;
+            }
 
             match self.tokens[self.current].token_type {
                 TokenType::Class
@@ -448,21 +784,97 @@ impl Parser {
                 | TokenType::Print
                 | TokenType::Return => return,
                 _ => {
+                    nesting = self.skip_brace(nesting);
                     self.advance();
                 }
             }
         }
     }
+
+    fn closes_enclosing_block(&self, nesting: usize) -> bool {
+        nesting == 0 && self.block_depth > 0 && self.check(&TokenType::RightBrace)
+    }
+
+    /// How deeply nested in skipped braces the parser is once the current token is skipped.
+    fn skip_brace(&self, nesting: usize) -> usize {
+        match self.tokens.get(self.current).map(|token| &token.token_type) {
+            Some(TokenType::LeftBrace) => nesting + 1,
+            Some(TokenType::RightBrace) => nesting.saturating_sub(1),
+            _ => nesting,
+        }
+    }
 }
 
 pub fn parenthesize_statements(statements: &[Stmt]) -> String {
-    let mut strings: Vec<String> = Vec::new();
-    for stmt in statements {
-        if let Stmt::Expression(expr) = stmt {
-            strings.push(parenthesize(expr));
+    let strings: Vec<String> = statements.iter().map(parenthesize_statement).collect();
+    strings.join("\n")
+}
+
+pub fn parenthesize_statement(stmt: &Stmt) -> String {
+    let join = |parts: Vec<String>| format!("({})", parts.join(" "));
+    match stmt {
+        Stmt::Expression(expr) => parenthesize(expr),
+        Stmt::Print(expr) => format!("(print {})", parenthesize(expr)),
+        Stmt::Var(name, initializer) => format!("(var {} {})", name.token_type, parenthesize(initializer)),
+        Stmt::Block(statements) => {
+            let mut parts = vec!["block".to_string()];
+            parts.extend(statements.iter().map(parenthesize_statement));
+            join(parts)
+        }
+        Stmt::If(condition, then_branch, else_branch) => {
+            let mut parts = vec![
+                "if".to_string(),
+                parenthesize(condition),
+                parenthesize_statement(then_branch),
+            ];
+            parts.extend(else_branch.iter().map(|stmt| parenthesize_statement(stmt)));
+            join(parts)
+        }
+        Stmt::While(condition, body, increment) => {
+            let mut parts = vec![
+                "while".to_string(),
+                parenthesize(condition),
+                parenthesize_statement(body),
+            ];
+            parts.extend(increment.iter().map(parenthesize));
+            join(parts)
+        }
+        Stmt::Function(declaration) => parenthesize_function("fun", declaration),
+        Stmt::Return(_, value) => {
+            let mut parts = vec!["return".to_string()];
+            parts.extend(value.iter().map(parenthesize));
+            join(parts)
+        }
+        Stmt::Break(_) => "(break)".to_string(),
+        Stmt::Continue(_) => "(continue)".to_string(),
+        Stmt::Class(name, superclass, methods) => {
+            let mut parts = vec!["class".to_string(), name.token_type.to_string()];
+            if let Some(superclass) = superclass {
+                parts.push(format!("< {}", parenthesize(superclass)));
+            }
+            parts.extend(
+                methods
+                    .iter()
+                    .map(|method| parenthesize_function("method", method)),
+            );
+            join(parts)
         }
     }
-    strings.join("\n")
+}
+
+fn parenthesize_function(kind: &str, declaration: &FunctionDecl) -> String {
+    let params: Vec<String> = declaration
+        .params
+        .iter()
+        .map(|param| param.token_type.to_string())
+        .collect();
+    let mut parts = vec![
+        kind.to_string(),
+        declaration.name.token_type.to_string(),
+        format!("({})", params.join(" ")),
+    ];
+    parts.extend(declaration.body.iter().map(parenthesize_statement));
+    format!("({})", parts.join(" "))
 }
 
 pub fn parenthesize(expr: &Expr) -> String {
@@ -486,14 +898,28 @@ pub fn parenthesize(expr: &Expr) -> String {
             parenthesize(expr_left),
             parenthesize(expr_right)
         ),
-        Expr::Variable(var_identifier) => format!("var {}", var_identifier),
+        Expr::Variable(var_identifier, _) => var_identifier.token_type.to_string(),
         Expr::Logical(expr_left, token, expr_right) => format!(
-            "{} {} {}",
-            parenthesize(expr_left),
+            "({} {} {})",
             token.token_type,
+            parenthesize(expr_left),
             parenthesize(expr_right)
         ),
-        Expr::Assign(token, expr) => format!("({} = {})", token, parenthesize(expr)),
+        Expr::Assign(token, expr, _) => format!("(= {} {})", token.token_type, parenthesize(expr)),
+        Expr::Call(callee, _, arguments) => {
+            let mut parts = vec!["call".to_string(), parenthesize(callee)];
+            parts.extend(arguments.iter().map(parenthesize));
+            format!("({})", parts.join(" "))
+        }
+        Expr::Get(object, name) => format!("(. {} {})", parenthesize(object), name.token_type),
+        Expr::Set(object, name, value) => format!(
+            "(= (. {} {}) {})",
+            parenthesize(object),
+            name.token_type,
+            parenthesize(value)
+        ),
+        Expr::This(_, _) => "this".to_string(),
+        Expr::Super(_, method, _) => format!("(super {})", method.token_type),
     }
 }
 
@@ -524,7 +950,7 @@ mod tests {
         let mut parser = Parser::new(tokens);
         let error = LoxError::Critical("No tokens parsed.".to_string());
         let actual_error = parser.parse().unwrap_err();
-        assert_eq!(actual_error, error);
+        assert_eq!(actual_error, vec![error]);
     }
 
     #[test]
@@ -572,9 +998,9 @@ mod tests {
         // (1 + 2)
         let tokens = vec![
             Token::new(TokenType::LeftParen, loc(1)),
-            Token::new(TokenType::Number(1f32), loc(1)),
+            Token::new(TokenType::Number(1f64), loc(1)),
             Token::new(TokenType::Plus, loc(1)),
-            Token::new(TokenType::Number(2f32), loc(1)),
+            Token::new(TokenType::Number(2f64), loc(1)),
             Token::new(TokenType::RightParen, loc(1)),
             Token::new(TokenType::SemiColon, loc(1)),
             Token::new(TokenType::Eof, loc(1)),
@@ -596,14 +1022,14 @@ mod tests {
         // (1 + 2) * 3 == 9
         let tokens = vec![
             Token::new(TokenType::LeftParen, loc(1)),
-            Token::new(TokenType::Number(1f32), loc(1)),
+            Token::new(TokenType::Number(1f64), loc(1)),
             Token::new(TokenType::Plus, loc(1)),
-            Token::new(TokenType::Number(2f32), loc(1)),
+            Token::new(TokenType::Number(2f64), loc(1)),
             Token::new(TokenType::RightParen, loc(1)),
             Token::new(TokenType::Star, loc(1)),
-            Token::new(TokenType::Number(3f32), loc(1)),
+            Token::new(TokenType::Number(3f64), loc(1)),
             Token::new(TokenType::EqualEqual, loc(1)),
-            Token::new(TokenType::Number(9f32), loc(1)),
+            Token::new(TokenType::Number(9f64), loc(1)),
             Token::new(TokenType::SemiColon, loc(1)),
             Token::new(TokenType::Eof, loc(1)),
         ];
@@ -626,4 +1052,211 @@ mod tests {
         let actual_ast = parser.parse();
         assert_eq!(actual_ast.unwrap(), expected_ast);
     }
+    #[test]
+    fn test_function_declaration() {
+        // fun add(a, b) { return a; }
+        let identifier = |name: &str| Token::new(TokenType::Identifier(name.to_string()), loc(1));
+        let tokens = vec![
+            Token::new(TokenType::Fun, loc(1)),
+            identifier("add"),
+            Token::new(TokenType::LeftParen, loc(1)),
+            identifier("a"),
+            Token::new(TokenType::Comma, loc(1)),
+            identifier("b"),
+            Token::new(TokenType::RightParen, loc(1)),
+            Token::new(TokenType::LeftBrace, loc(1)),
+            Token::new(TokenType::Return, loc(1)),
+            identifier("a"),
+            Token::new(TokenType::SemiColon, loc(1)),
+            Token::new(TokenType::RightBrace, loc(1)),
+            Token::new(TokenType::Eof, loc(1)),
+        ];
+
+        let expected_ast = vec![Stmt::Function(Rc::new(FunctionDecl {
+            name: identifier("add"),
+            params: vec![identifier("a"), identifier("b")],
+            body: vec![Stmt::Return(
+                Token::new(TokenType::Return, loc(1)),
+                Some(Expr::Variable(identifier("a"), ScopeDepth::default())),
+            )],
+        }))];
+
+        let mut parser = Parser::new(tokens);
+        assert_eq!(parser.parse().unwrap(), expected_ast);
+    }
+
+    #[test]
+    fn test_nested_call_expr() {
+        // foo(1)(2, 3);
+        let tokens = vec![
+            Token::new(TokenType::Identifier("foo".to_string()), loc(1)),
+            Token::new(TokenType::LeftParen, loc(1)),
+            Token::new(TokenType::Number(1f64), loc(1)),
+            Token::new(TokenType::RightParen, loc(1)),
+            Token::new(TokenType::LeftParen, loc(1)),
+            Token::new(TokenType::Number(2f64), loc(1)),
+            Token::new(TokenType::Comma, loc(1)),
+            Token::new(TokenType::Number(3f64), loc(1)),
+            Token::new(TokenType::RightParen, loc(1)),
+            Token::new(TokenType::SemiColon, loc(1)),
+            Token::new(TokenType::Eof, loc(1)),
+        ];
+
+        let number = |x: f64| Expr::Literal(loc(1), Literal::Number(x));
+        let paren = Token::new(TokenType::RightParen, loc(1));
+        let expected_ast = vec![Stmt::Expression(Expr::Call(
+            Box::new(Expr::Call(
+                Box::new(Expr::Variable(
+                    Token::new(TokenType::Identifier("foo".to_string()), loc(1)),
+                    ScopeDepth::default(),
+                )),
+                paren.clone(),
+                vec![number(1.0)],
+            )),
+            paren,
+            vec![number(2.0), number(3.0)],
+        ))];
+
+        let mut parser = Parser::new(tokens);
+        assert_eq!(parser.parse().unwrap(), expected_ast);
+    }
+    #[test]
+    fn test_property_assignment_becomes_set_expr() {
+        // this.a.b = 1;
+        let identifier = |name: &str| Token::new(TokenType::Identifier(name.to_string()), loc(1));
+        let tokens = vec![
+            Token::new(TokenType::This, loc(1)),
+            Token::new(TokenType::Dot, loc(1)),
+            identifier("a"),
+            Token::new(TokenType::Dot, loc(1)),
+            identifier("b"),
+            Token::new(TokenType::Equal, loc(1)),
+            Token::new(TokenType::Number(1f64), loc(1)),
+            Token::new(TokenType::SemiColon, loc(1)),
+            Token::new(TokenType::Eof, loc(1)),
+        ];
+
+        let expected_ast = vec![Stmt::Expression(Expr::Set(
+            Box::new(Expr::Get(
+                Box::new(Expr::This(
+                    Token::new(TokenType::This, loc(1)),
+                    ScopeDepth::default(),
+                )),
+                identifier("a"),
+            )),
+            identifier("b"),
+            Box::new(Expr::Literal(loc(1), Literal::Number(1.0))),
+        ))];
+
+        let mut parser = Parser::new(tokens);
+        assert_eq!(parser.parse().unwrap(), expected_ast);
+    }
+
+    #[test]
+    fn test_ternary_is_right_associative() {
+        // true ? 1 : false ? 2 : 3;
+        let tokens = vec![
+            Token::new(TokenType::True, loc(1)),
+            Token::new(TokenType::Question, loc(1)),
+            Token::new(TokenType::Number(1f64), loc(1)),
+            Token::new(TokenType::Colon, loc(1)),
+            Token::new(TokenType::False, loc(1)),
+            Token::new(TokenType::Question, loc(1)),
+            Token::new(TokenType::Number(2f64), loc(1)),
+            Token::new(TokenType::Colon, loc(1)),
+            Token::new(TokenType::Number(3f64), loc(1)),
+            Token::new(TokenType::SemiColon, loc(1)),
+            Token::new(TokenType::Eof, loc(1)),
+        ];
+
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+        assert_eq!(
+            parenthesize_statements(&ast),
+            "(ternary true 1 (ternary false 2 3))"
+        );
+    }
+
+    fn parse_source(source: &str) -> (Vec<Stmt>, Vec<LoxError>) {
+        let tokens = crate::scanner::SourceCode::new(source, "unittest.lox".to_string()).scan_tokens();
+        Parser::new(tokens).parse_recovering()
+    }
+
+    fn error_lines(errors: &[LoxError]) -> Vec<usize> {
+        errors
+            .iter()
+            .map(|err| err.location().and_then(|location| location.line()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_every_syntax_error_is_reported() {
+        let source = "\
+var = 1;
+print 1 +;
+var ok = 2;
+print (ok;
+fun f( { return 1; }
+print ok;
+class { }
+1 + * 2;
+";
+        let (statements, errors) = parse_source(source);
+        assert_eq!(error_lines(&errors), vec![1, 2, 4, 5, 7, 8]);
+        // `var ok = 2;` and `print ok;` survive.
+        assert_eq!(statements.len(), 2);
+    }
+
+    #[test]
+    fn test_recovery_stays_inside_block() {
+        let source = "\
+{
+  print 1 +;
+  var inner = { 1 };
+  print 2;
+}
+print 3;
+";
+        let (statements, errors) = parse_source(source);
+        assert_eq!(error_lines(&errors), vec![2, 3]);
+        assert!(matches!(&statements[..], [Stmt::Block(body), Stmt::Print(_)] if body.len() == 1));
+    }
+
+    #[test]
+    fn test_parse_returns_all_errors() {
+        let tokens =
+            crate::scanner::SourceCode::new("print;\nprint;", "unittest.lox".to_string()).scan_tokens();
+        let errors = Parser::new(tokens).parse().unwrap_err();
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn test_lexical_errors_are_reported_with_syntax_errors() {
+        let (statements, errors) = parse_source("print 1 # 2;\nprint 3;\nprint ;");
+        let messages: Vec<&str> = errors.iter().map(|err| err.message()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Unexpected character '#'.",
+                "Expect ; after value.\nUnexpected token is Number(2.0) Error at -> unittest.lox:1:11",
+                "Expected expression and found None."
+            ]
+        );
+        assert_eq!(statements.len(), 1);
+    }
+
+    #[test]
+    fn test_parenthesize_statements() {
+        let source = "\
+var a = 1;
+class B < A { m(x) { return x or a; } }
+for (var i = 0; i < 1;) { if (a) break; else a = 2; }";
+        let (statements, errors) = parse_source(source);
+        assert_eq!(errors, vec![]);
+        let expected = "\
+(var a 1)
+(class B < A (method m (x) (return (Or x a))))
+(block (var i 0) (while (Less i 1) (block (if a (break) (= a 2)))))";
+        assert_eq!(parenthesize_statements(&statements), expected);
+    }
 }
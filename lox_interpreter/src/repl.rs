@@ -0,0 +1,318 @@
+use std::fs;
+use std::io::{Stderr, Write};
+
+use crate::core::diagnostics::Diagnostic;
+use crate::core::errors::LoxError;
+use crate::core::sources::Sources;
+use crate::interpreter::{Interpreter, InterpreterState};
+use crate::parser::Parser;
+use crate::scanner::SourceCode;
+use crate::tokens::{Token, TokenType};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplStatus {
+    /// The input so far has been run and the REPL is ready for a new statement.
+    Ready,
+    /// The input has unclosed braces or parentheses so more lines are needed before running it.
+    Continuation,
+    Quit,
+}
+
+/// An interactive session. The interpreter state lives as long as the session so definitions
+/// from one line can be used on the next. Program output goes to the state's writer and errors
+/// go to the error writer, which is stderr unless another is given.
+pub struct Repl<W: Write, E: Write = Stderr> {
+    state: InterpreterState<W>,
+    errors: E,
+    buffer: String,
+    color: bool,
+    // Recent input by the name it was given, so errors raised later by functions defined in
+    // earlier input can still show the code they point at.
+    sources: Sources,
+    inputs: usize,
+}
+
+impl<W: Write> Repl<W> {
+    pub fn new(state: InterpreterState<W>) -> Self {
+        Repl::with_errors(state, std::io::stderr())
+    }
+}
+
+impl<W: Write, E: Write> Repl<W, E> {
+    pub fn with_errors(state: InterpreterState<W>, errors: E) -> Self {
+        Repl {
+            state,
+            errors,
+            buffer: String::new(),
+            color: false,
+            sources: Sources::default(),
+            inputs: 0,
+        }
+    }
+
+    /// Turns ANSI colours in error messages on or off. They are off by default.
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    pub fn state(&self) -> &InterpreterState<W> {
+        &self.state
+    }
+
+    pub fn errors(&self) -> &E {
+        &self.errors
+    }
+
+    pub fn prompt(&self) -> &'static str {
+        match self.buffer.is_empty() {
+            true => "> ",
+            false => "... ",
+        }
+    }
+
+    pub fn handle_line(&mut self, line: &str) -> ReplStatus {
+        if self.buffer.is_empty() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                return ReplStatus::Ready;
+            }
+            if let Some(command) = trimmed.strip_prefix(':') {
+                return self.meta_command(command);
+            }
+        }
+
+        self.buffer.push_str(line);
+        self.buffer.push('\n');
+        if !is_balanced(&self.buffer) {
+            return ReplStatus::Continuation;
+        }
+
+        let source = std::mem::take(&mut self.buffer);
+        self.run_input(&source);
+        ReplStatus::Ready
+    }
+
+    fn meta_command(&mut self, command: &str) -> ReplStatus {
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, ""),
+        };
+        match name {
+            "quit" | "q" => return ReplStatus::Quit,
+            "help" => {
+                self.output(":env          List the global bindings.");
+                self.output(":load <file>  Run a file in this session.");
+                self.output(":reset        Remove every binding.");
+                self.output(":quit         Leave the REPL.");
+            }
+            "reset" => {
+                self.state.reset();
+                self.output("Environment reset.");
+            }
+            "env" => {
+                let bindings = self.state.environment().globals();
+                if bindings.is_empty() {
+                    self.output("No bindings.");
+                }
+                for (key, value) in bindings {
+                    self.output(&format!("{} = {}", key, value));
+                }
+            }
+            "load" if argument.is_empty() => self.output("Usage: :load <file>"),
+            "load" => match fs::read_to_string(argument) {
+                Ok(source) => self.run(&source, argument),
+                Err(err) => self.error(&format!("Could not load {}: {}", argument, err)),
+            },
+            _ => self.error(&format!(
+                "Unknown command ':{}'. Type :help for a list of commands.",
+                name
+            )),
+        }
+        ReplStatus::Ready
+    }
+
+    /// Runs a line of input. A lone expression may leave off its trailing semicolon.
+    fn run_input(&mut self, source: &str) {
+        self.inputs += 1;
+        let filename = format!("repl[{}]", self.inputs);
+        let trimmed = source.trim_end();
+        if !trimmed.ends_with(';') && !trimmed.ends_with('}') {
+            let with_semicolon = format!("{};", trimmed);
+            if Parser::new(scan(&with_semicolon, &filename)).parse().is_ok() {
+                return self.run(&with_semicolon, &filename);
+            }
+        }
+        self.run(source, &filename);
+    }
+
+    fn run(&mut self, source: &str, filename: &str) {
+        self.sources.insert(filename, source);
+        let tokens = scan(source, filename);
+        if tokens.len() == 1 {
+            // Only whitespace and comments, so there is nothing to run.
+            return;
+        }
+        let ast = match Parser::new(tokens).parse() {
+            Ok(ast) => ast,
+            Err(errors) => {
+                for err in errors {
+                    self.report(&err);
+                }
+                return;
+            }
+        };
+        let interpreter = Interpreter::new(ast);
+        let (value, errors) = interpreter.interpret_with_value(&mut self.state);
+        for err in errors {
+            self.report(&err);
+        }
+        if let Some(value) = value {
+            self.output(&value.to_string());
+        }
+    }
+
+    fn report(&mut self, error: &LoxError) {
+        let source = error
+            .location()
+            .and_then(|location| location.filename())
+            .and_then(|filename| self.sources.get(filename));
+        let rendered = Diagnostic::from_error(error).render(source, self.color);
+        self.error(rendered.trim_end());
+    }
+
+    // There is nowhere better to report a failure to write to the console, so it is ignored.
+    fn output(&mut self, text: &str) {
+        let _ = writeln!(self.state.writer(), "{}", text);
+    }
+
+    fn error(&mut self, text: &str) {
+        let _ = writeln!(self.errors, "{}", text);
+    }
+}
+
+fn scan(source: &str, filename: &str) -> Vec<Token> {
+    SourceCode::new(source, filename.to_string()).scan_tokens()
+}
+
+/// Whether every `(` and `{` in the source has been closed.
+pub fn is_balanced(source: &str) -> bool {
+    let mut depth: i32 = 0;
+    for token in scan(source, "repl") {
+        match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::sources::DEFAULT_SOURCE_CAPACITY;
+    use rstest::*;
+
+    fn repl() -> Repl<Vec<u8>, Vec<u8>> {
+        Repl::with_errors(InterpreterState::default(), Vec::new())
+    }
+
+    fn run_lines(lines: &[&str]) -> String {
+        let mut repl = repl();
+        for line in lines {
+            repl.handle_line(line);
+        }
+        repl.state().get_writer().to_string()
+    }
+
+    /// What the lines print and what errors they report.
+    fn run_lines_with_errors(lines: &[&str]) -> (String, String) {
+        let mut repl = repl();
+        for line in lines {
+            repl.handle_line(line);
+        }
+        let errors = String::from_utf8(repl.errors().clone()).unwrap();
+        (repl.state().get_writer().to_string(), errors)
+    }
+
+    #[rstest]
+    #[case("print 1;", true)]
+    #[case("fun f() {", false)]
+    #[case("fun f() { print (1 + 2); }", true)]
+    #[case("print \"{\";", true)]
+    #[case("foo(1,", false)]
+    fn test_is_balanced(#[case] source: &str, #[case] expected: bool) {
+        assert_eq!(is_balanced(source), expected);
+    }
+
+    #[test]
+    fn test_definitions_survive_between_lines() {
+        assert_eq!(run_lines(&["var x = 1;", "print x;"]), "1\n");
+    }
+
+    #[test]
+    fn test_bare_expressions_are_echoed() {
+        assert_eq!(run_lines(&["var x = 40;", "x + 2;", "x * 2"]), "42\n80\n");
+    }
+
+    #[test]
+    fn test_multi_line_input() {
+        let mut repl = repl();
+        assert_eq!(repl.handle_line("fun add(a, b) {"), ReplStatus::Continuation);
+        assert_eq!(repl.prompt(), "... ");
+        assert_eq!(repl.handle_line("  return a + b;"), ReplStatus::Continuation);
+        assert_eq!(repl.handle_line("}"), ReplStatus::Ready);
+        assert_eq!(repl.prompt(), "> ");
+        repl.handle_line("print add(1, 2);");
+        assert_eq!(repl.state().get_writer(), "3\n");
+    }
+
+    #[test]
+    fn test_env_and_reset_commands() {
+        let output = run_lines(&["var b = true;", "var a = \"one\";", ":env", ":reset", ":env"]);
+        let expected = "a = one\nb = true\nclock = <native fn>\nEnvironment reset.\nclock = <native fn>\n";
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_quit_command() {
+        let mut repl = repl();
+        assert_eq!(repl.handle_line(":quit"), ReplStatus::Quit);
+    }
+
+    #[test]
+    fn test_errors_show_the_input_they_came_from() {
+        let (output, errors) =
+            run_lines_with_errors(&["print 1;", "fun f() {", "  return nope;", "}", "f();"]);
+        assert_eq!(output, "1\n");
+        let expected = "\
+runtime error: Undefined variable: nope
+ --> repl[2]:2:10
+  |
+2 |   return nope;
+  |          ^^^^ undefined variable
+  = note: in f(), called at repl[3]:1:3
+";
+        assert_eq!(errors, expected);
+    }
+
+    #[test]
+    fn test_only_recent_input_is_kept() {
+        let mut repl = repl();
+        for _ in 0..DEFAULT_SOURCE_CAPACITY + 10 {
+            repl.handle_line("1;");
+        }
+        assert_eq!(repl.sources.len(), DEFAULT_SOURCE_CAPACITY);
+        assert_eq!(repl.sources.get("repl[1]"), None);
+    }
+
+    #[test]
+    fn test_unknown_commands_are_errors() {
+        let (output, errors) = run_lines_with_errors(&[":nope"]);
+        assert_eq!(output, "");
+        assert_eq!(
+            errors,
+            "Unknown command ':nope'. Type :help for a list of commands.\n"
+        );
+    }
+}
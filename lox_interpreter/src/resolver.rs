@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+
+use crate::core::errors::LoxError;
+use crate::parser::{Expr, FunctionDecl, ScopeDepth, Stmt};
+use crate::tokens::Token;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Method,
+    Initializer,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+/// A static pass over the AST that runs before the interpreter. It records on every variable
+/// expression how many scopes out its declaration lives and reports scoping mistakes that can be
+/// found without running the program.
+pub struct Resolver {
+    // Each scope maps a name to whether its initializer has finished resolving.
+    scopes: Vec<HashMap<String, bool>>,
+    current_function: FunctionType,
+    current_class: ClassType,
+    errors: Vec<LoxError>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver {
+            scopes: Vec::new(),
+            current_function: FunctionType::None,
+            current_class: ClassType::None,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn resolve(mut self, statements: &[Stmt]) -> Vec<LoxError> {
+        self.resolve_statements(statements);
+        self.errors
+    }
+
+    fn resolve_statements(&mut self, statements: &[Stmt]) {
+        for stmt in statements {
+            self.resolve_stmt(stmt);
+        }
+    }
+
+    fn resolve_stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.resolve_expr(expr),
+            Stmt::Var(name, initializer) => {
+                self.declare(name);
+                self.resolve_expr(initializer);
+                self.define(name);
+            }
+            Stmt::Block(statements) => {
+                self.begin_scope();
+                self.resolve_statements(statements);
+                self.end_scope();
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_stmt(else_branch);
+                }
+            }
+            Stmt::While(condition, body, increment) => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(body);
+                if let Some(increment) = increment {
+                    self.resolve_expr(increment);
+                }
+            }
+            Stmt::Break(_) | Stmt::Continue(_) => {}
+            Stmt::Function(declaration) => {
+                self.declare(&declaration.name);
+                self.define(&declaration.name);
+                self.resolve_function(declaration, FunctionType::Function);
+            }
+            Stmt::Return(keyword, value) => {
+                if self.current_function == FunctionType::None {
+                    self.error(keyword, "Can't return from top-level code.");
+                }
+                if let Some(value) = value {
+                    if self.current_function == FunctionType::Initializer {
+                        self.error(keyword, "Can't return a value from an initializer.");
+                    }
+                    self.resolve_expr(value);
+                }
+            }
+            Stmt::Class(name, superclass, methods) => {
+                let enclosing_class = self.current_class;
+                self.current_class = ClassType::Class;
+                self.declare(name);
+                self.define(name);
+
+                if let Some(superclass) = superclass {
+                    self.current_class = ClassType::Subclass;
+                    self.resolve_expr(superclass);
+                    self.begin_scope();
+                    self.define_name("super");
+                }
+
+                self.begin_scope();
+                self.define_name("this");
+                for method in methods {
+                    let function_type = match method.name.token_type.to_string().as_str() {
+                        "init" => FunctionType::Initializer,
+                        _ => FunctionType::Method,
+                    };
+                    self.resolve_function(method, function_type);
+                }
+                self.end_scope();
+
+                if superclass.is_some() {
+                    self.end_scope();
+                }
+                self.current_class = enclosing_class;
+            }
+        }
+    }
+
+    fn resolve_function(&mut self, declaration: &FunctionDecl, function_type: FunctionType) {
+        let enclosing_function = self.current_function;
+        self.current_function = function_type;
+        self.begin_scope();
+        for param in &declaration.params {
+            self.declare(param);
+            self.define(param);
+        }
+        self.resolve_statements(&declaration.body);
+        self.end_scope();
+        self.current_function = enclosing_function;
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Variable(name, depth) => {
+                let key = name.token_type.to_string();
+                if let Some(false) = self.scopes.last().and_then(|scope| scope.get(&key)) {
+                    self.error(name, "Can't read local variable in its own initializer.");
+                }
+                self.resolve_local(&key, depth);
+            }
+            Expr::Assign(name, value, depth) => {
+                self.resolve_expr(value);
+                self.resolve_local(&name.token_type.to_string(), depth);
+            }
+            Expr::This(keyword, depth) => match self.current_class {
+                ClassType::None => self.error(keyword, "Can't use 'this' outside of a class."),
+                _ => self.resolve_local("this", depth),
+            },
+            Expr::Super(keyword, _, depth) => match self.current_class {
+                ClassType::None => self.error(keyword, "Can't use 'super' outside of a class."),
+                ClassType::Class => self.error(keyword, "Can't use 'super' in a class with no superclass."),
+                ClassType::Subclass => self.resolve_local("super", depth),
+            },
+            Expr::Binary(left, _, right) | Expr::Logical(left, _, right) => {
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            }
+            Expr::Call(callee, _, arguments) => {
+                self.resolve_expr(callee);
+                for argument in arguments {
+                    self.resolve_expr(argument);
+                }
+            }
+            Expr::Get(object, _) => self.resolve_expr(object),
+            Expr::Set(object, _, value) => {
+                self.resolve_expr(value);
+                self.resolve_expr(object);
+            }
+            Expr::Grouping(expr) | Expr::Unary(_, expr) => self.resolve_expr(expr),
+            Expr::Ternary(condition, left, right) => {
+                self.resolve_expr(condition);
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            }
+            Expr::Literal(_, _) => {}
+        }
+    }
+
+    /// Records how far out the innermost declaration of `key` is. Names that aren't found in any
+    /// local scope are left unresolved and looked up as globals at runtime.
+    fn resolve_local(&mut self, key: &str, depth: &ScopeDepth) {
+        for (i, scope) in self.scopes.iter().rev().enumerate() {
+            if scope.contains_key(key) {
+                depth.set(i);
+                return;
+            }
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn declare(&mut self, name: &Token) {
+        let key = name.token_type.to_string();
+        if let Some(scope) = self.scopes.last_mut() {
+            if scope.contains_key(&key) {
+                self.error(name, "Already a variable with this name in this scope.");
+                return;
+            }
+            scope.insert(key, false);
+        }
+    }
+
+    fn define(&mut self, name: &Token) {
+        self.define_name(&name.token_type.to_string());
+    }
+
+    fn define_name(&mut self, key: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(key.to_string(), true);
+        }
+    }
+
+    fn error(&mut self, token: &Token, message: &str) {
+        self.errors
+            .push(LoxError::SyntaxError(token.location.clone(), message.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runhelpers::raw_source_to_ast;
+
+    fn resolve(source: &str) -> (Vec<Stmt>, Vec<String>) {
+        let statements = raw_source_to_ast(source, "unittest.lox").unwrap();
+        let errors = Resolver::new().resolve(&statements);
+        let messages = errors
+            .iter()
+            .map(|err| match err {
+                LoxError::SyntaxError(_, msg) => msg.clone(),
+                other => panic!("Expected a syntax error and got {}", other),
+            })
+            .collect();
+        (statements, messages)
+    }
+
+    #[test]
+    fn test_local_variable_depths() {
+        let (statements, errors) = resolve("var a; { var b; { a; b; } }");
+        assert!(errors.is_empty());
+        let Stmt::Block(outer) = &statements[1] else {
+            panic!("Expected a block.")
+        };
+        let Stmt::Block(inner) = &outer[1] else {
+            panic!("Expected a block.")
+        };
+        let depth = |stmt: &Stmt| match stmt {
+            Stmt::Expression(Expr::Variable(_, depth)) => depth.get(),
+            _ => panic!("Expected a variable expression."),
+        };
+        assert_eq!(depth(&inner[0]), None);
+        assert_eq!(depth(&inner[1]), Some(1));
+    }
+
+    #[test]
+    fn test_scoping_errors() {
+        let cases = [
+            (
+                "{ var a = a; }",
+                "Can't read local variable in its own initializer.",
+            ),
+            (
+                "{ var a; var a; }",
+                "Already a variable with this name in this scope.",
+            ),
+            ("return 1;", "Can't return from top-level code."),
+            (
+                "class A { init() { return 1; } }",
+                "Can't return a value from an initializer.",
+            ),
+            ("print this;", "Can't use 'this' outside of a class."),
+            (
+                "class A { m() { super.m(); } }",
+                "Can't use 'super' in a class with no superclass.",
+            ),
+        ];
+        for (source, expected) in cases {
+            let (_, errors) = resolve(source);
+            assert_eq!(errors, vec![expected.to_string()], "source: {}", source);
+        }
+    }
+
+    #[test]
+    fn test_globals_can_be_redeclared() {
+        let (_, errors) = resolve("var a = 1; var a = a;");
+        assert!(errors.is_empty());
+    }
+}
@@ -25,14 +25,14 @@ fn report(line: usize, location: String, message: String) {
     println!("[line {}] Error {}: {}", line, location, message);
 }
 
-pub fn raw_source_to_ast(source: &str, filename: &str) -> Result<Vec<Stmt>, LoxError> {
+pub fn raw_source_to_ast(source: &str, filename: &str) -> Result<Vec<Stmt>, Vec<LoxError>> {
     let mut source_code = SourceCode::new(source, filename.to_string());
     let tokens = source_code.scan_tokens();
     let mut parser = Parser::new(tokens);
     parser.parse()
 }
 
-pub fn filepath_to_ast(filepath: &str) -> Result<Vec<Stmt>, LoxError> {
+pub fn filepath_to_ast(filepath: &str) -> Result<Vec<Stmt>, Vec<LoxError>> {
     let raw_source = load_source(filepath);
     let mut source_code = SourceCode::new(&raw_source, filepath.to_string());
     let tokens = source_code.scan_tokens();
@@ -1,5 +1,5 @@
 use crate::{
-    core::location::Location,
+    core::location::{Location, Span},
     tokens::{Token, TokenType},
 };
 use std::iter::Peekable;
@@ -8,12 +8,14 @@ pub struct SourceCode<'a> {
     pub source: String,
     pub line: usize,
     pub filename: String,
+    // Byte offset of the first character of the current line, used to work out columns.
+    line_start: usize,
     indices: Peekable<std::str::CharIndices<'a>>,
 }
 
 pub fn is_valid_for_identifier(c: char) -> bool {
     match c {
-        'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '?' => true,
+        'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => true,
         _ => false,
     }
 }
@@ -30,6 +32,7 @@ pub fn identifier_or_keyword_to_tokentype(identifier: &str) -> TokenType {
         "and" => TokenType::And,
         "break" => TokenType::Break,
         "class" => TokenType::Class,
+        "continue" => TokenType::Continue,
         "else" => TokenType::Else,
         "false" => TokenType::False,
         "fun" => TokenType::Fun,
@@ -54,6 +57,7 @@ impl<'a> SourceCode<'a> {
             source: source.to_string(),
             line: 1,
             filename,
+            line_start: 0,
             indices: source.char_indices().peekable(),
         }
     }
@@ -62,6 +66,18 @@ impl<'a> SourceCode<'a> {
         Location::Line(self.filename.clone(), self.line)
     }
 
+    /// The location of the source between the byte offsets `start` and `end`, which must begin on
+    /// the current line.
+    pub fn span(&self, start: usize, end: usize) -> Location {
+        let column = self.source[self.line_start..start].chars().count() + 1;
+        Location::new_span(self.filename.clone(), Span::new(start, end, self.line, column))
+    }
+
+    fn new_line(&mut self, newline_index: usize) {
+        self.line += 1;
+        self.line_start = newline_index + 1;
+    }
+
     pub fn take_while_inclusive<F: Fn(char) -> bool>(
         &mut self,
         pred: F,
@@ -81,6 +97,7 @@ impl<'a> SourceCode<'a> {
 
     pub fn peek_match_and_add(
         &mut self,
+        start: usize,
         match_char: char,
         match_token_type: TokenType,
         not_match_token_type: TokenType,
@@ -88,23 +105,148 @@ impl<'a> SourceCode<'a> {
     ) {
         match self.indices.peek() {
             Some(pair) if pair.1 == match_char => {
-                tokens.push(Token::new(match_token_type, self.loc()));
+                tokens.push(Token::new(match_token_type, self.span(start, start + 2)));
                 self.indices.next();
             }
             Some(_) | None => {
-                tokens.push(Token::new(not_match_token_type, self.loc()));
+                tokens.push(Token::new(not_match_token_type, self.span(start, start + 1)));
+            }
+        }
+    }
+
+    /// Scans a string literal whose opening quote is at `start`. Strings can span lines. An
+    /// invalid escape sequence adds an error token but the string is still scanned to its end.
+    fn string(&mut self, start: usize, tokens: &mut Vec<Token>) {
+        let opening = self.span(start, start + 1);
+        let mut literal = String::new();
+        while let Some((i, c)) = self.indices.next() {
+            match c {
+                '"' => {
+                    let location = opening.to(&self.span(i, i + 1));
+                    tokens.push(Token::new(TokenType::String(literal), location));
+                    return;
+                }
+                '\\' => match self.escape(i) {
+                    Ok(escaped) => literal.push(escaped),
+                    Err(error) => tokens.push(error),
+                },
+                '\n' => {
+                    literal.push(c);
+                    self.new_line(i);
+                }
+                _ => literal.push(c),
+            }
+        }
+        let end = self.source.len();
+        tokens.push(Token::new(
+            TokenType::Error("Unterminated string.".to_string()),
+            opening.to(&self.span(end, end)),
+        ));
+    }
+
+    /// Scans the escape sequence following the backslash at `start`: `\n`, `\t`, `\"`, `\\` or
+    /// `\u{...}` with up to six hex digits.
+    fn escape(&mut self, start: usize) -> Result<char, Token> {
+        let (i, c) = match self.indices.peek() {
+            Some(&(i, c)) if c != '\n' => (i, c),
+            // A line break is left for the string to count and a missing closing quote is
+            // reported as an unterminated string.
+            _ => return Err(self.error_token(start, start + 1, "Unfinished escape sequence.")),
+        };
+        self.indices.next();
+        let escaped = match c {
+            'n' => '\n',
+            't' => '\t',
+            '"' => '"',
+            '\\' => '\\',
+            'u' => return self.unicode_escape(start, i + 1),
+            _ => {
+                let end = i + c.len_utf8();
+                let message = format!("Invalid escape sequence '{}'.", &self.source[start..end]);
+                return Err(self.error_token(start, end, &message));
+            }
+        };
+        Ok(escaped)
+    }
+
+    /// Scans the `{...}` of a `\u{...}` escape starting at byte offset `brace`.
+    fn unicode_escape(&mut self, start: usize, brace: usize) -> Result<char, Token> {
+        let mut end = brace;
+        let mut closed = false;
+        if let Some(&(_, '{')) = self.indices.peek() {
+            self.indices.next();
+            end += 1;
+            while let Some(&(i, c)) = self.indices.peek() {
+                if c == '}' {
+                    self.indices.next();
+                    end = i + 1;
+                    closed = true;
+                    break;
+                }
+                if !c.is_ascii_hexdigit() {
+                    break;
+                }
+                self.indices.next();
+                end = i + 1;
+            }
+        }
+
+        let digits = match closed {
+            true => &self.source[brace + 1..end - 1],
+            false => "",
+        };
+        let code_point = match digits.len() {
+            1..=6 => u32::from_str_radix(digits, 16).ok().and_then(char::from_u32),
+            _ => None,
+        };
+        code_point.ok_or_else(|| {
+            let message = format!("Invalid unicode escape '{}'.", &self.source[start..end]);
+            self.error_token(start, end, &message)
+        })
+    }
+
+    /// Skips a `/* ... */` comment whose opening is at `start`. Block comments nest, so every
+    /// `/*` inside needs its own `*/`.
+    fn block_comment(&mut self, start: usize, tokens: &mut Vec<Token>) {
+        let opening = self.span(start, start + 2);
+        let mut depth = 1;
+        while let Some((i, c)) = self.indices.next() {
+            match (c, self.indices.peek()) {
+                ('/', Some(&(_, '*'))) => {
+                    self.indices.next();
+                    depth += 1;
+                }
+                ('*', Some(&(_, '/'))) => {
+                    self.indices.next();
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                ('\n', _) => self.new_line(i),
+                _ => {}
             }
         }
+        let end = self.source.len();
+        tokens.push(Token::new(
+            TokenType::Error("Unterminated block comment.".to_string()),
+            opening.to(&self.span(end, end)),
+        ));
+    }
+
+    fn error_token(&self, start: usize, end: usize, message: &str) -> Token {
+        Token::new(TokenType::Error(message.to_string()), self.span(start, end))
     }
 
     pub fn scan_tokens(&mut self) -> Vec<Token> {
         let mut tokens: Vec<Token> = Vec::new();
         while let Some((i, c)) = self.indices.next() {
-            let location = self.loc();
+            let location = self.span(i, i + c.len_utf8());
             match c {
                 ' ' => {}
                 '\t' => {}
-                '\n' => self.line += 1,
+                '\r' => {}
+                '\n' => self.new_line(i),
                 '(' => tokens.push(Token::new(TokenType::LeftParen, location)),
                 ')' => tokens.push(Token::new(TokenType::RightParen, location)),
                 '{' => tokens.push(Token::new(TokenType::LeftBrace, location)),
@@ -115,41 +257,50 @@ impl<'a> SourceCode<'a> {
                 '+' => tokens.push(Token::new(TokenType::Plus, location)),
                 ';' => tokens.push(Token::new(TokenType::SemiColon, location)),
                 '*' => tokens.push(Token::new(TokenType::Star, location)),
+                '?' => tokens.push(Token::new(TokenType::Question, location)),
+                ':' => tokens.push(Token::new(TokenType::Colon, location)),
                 '/' => match self.indices.peek() {
                     Some(indice) if indice.1 == '/' => {
-                        self.indices.find(|x| x.1 == '\n');
-                        self.line += 1;
+                        if let Some((newline_index, _)) = self.indices.find(|x| x.1 == '\n') {
+                            self.new_line(newline_index);
+                        }
+                    }
+                    Some(indice) if indice.1 == '*' => {
+                        self.indices.next();
+                        self.block_comment(i, &mut tokens);
                     }
                     _ => tokens.push(Token::new(TokenType::Slash, location)),
                 },
-                '!' => self.peek_match_and_add('=', TokenType::BangEqual, TokenType::Bang, &mut tokens),
-                '=' => self.peek_match_and_add('=', TokenType::EqualEqual, TokenType::Equal, &mut tokens),
-                '<' => self.peek_match_and_add('=', TokenType::LessEqual, TokenType::Less, &mut tokens),
-                '>' => self.peek_match_and_add('=', TokenType::GreaterEqual, TokenType::Greater, &mut tokens),
+                '!' => self.peek_match_and_add(i, '=', TokenType::BangEqual, TokenType::Bang, &mut tokens),
+                '=' => self.peek_match_and_add(i, '=', TokenType::EqualEqual, TokenType::Equal, &mut tokens),
+                '<' => self.peek_match_and_add(i, '=', TokenType::LessEqual, TokenType::Less, &mut tokens),
+                '>' => {
+                    self.peek_match_and_add(i, '=', TokenType::GreaterEqual, TokenType::Greater, &mut tokens)
+                }
                 '0'..='9' => {
                     let (start, end) = self.take_while_inclusive(is_valid_for_number, (i, c));
-                    let number = self.source[start..end].parse::<f32>().unwrap();
-                    tokens.push(Token::new(TokenType::Number(number), location));
-                }
-                '"' => {
-                    let string_literal: String = self
-                        .indices
-                        .by_ref()
-                        .take_while(|x| x.1 != '"')
-                        .map(|x| x.1)
-                        .collect();
-                    tokens.push(Token::new(TokenType::String(string_literal), location))
+                    let lexeme = &self.source[start..end];
+                    let token_type = match lexeme.parse::<f64>() {
+                        Ok(number) => TokenType::Number(number),
+                        Err(_) => TokenType::Error(format!("Invalid number '{}'.", lexeme)),
+                    };
+                    tokens.push(Token::new(token_type, self.span(start, end)));
                 }
+                '"' => self.string(i, &mut tokens),
                 'a'..='z' | 'A'..='Z' | '_' => {
                     let (start, end) = self.take_while_inclusive(is_valid_for_identifier, (i, c));
                     let token_lexeme = &self.source[start..end];
                     let token_type = identifier_or_keyword_to_tokentype(token_lexeme);
-                    tokens.push(Token::new(token_type, location));
+                    tokens.push(Token::new(token_type, self.span(start, end)));
                 }
-                _ => {}
+                _ => tokens.push(Token::new(
+                    TokenType::Error(format!("Unexpected character '{}'.", c)),
+                    location,
+                )),
             }
         }
-        tokens.push(Token::new(TokenType::Eof, self.loc()));
+        let end = self.source.len();
+        tokens.push(Token::new(TokenType::Eof, self.span(end, end)));
         tokens
     }
 }
@@ -159,8 +310,8 @@ mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
 
-    fn loc(line: usize) -> Location {
-        Location::Line("unittest.lox".to_string(), line)
+    fn loc(line: usize, column: usize, start: usize, end: usize) -> Location {
+        Location::new_span("unittest.lox".to_string(), Span::new(start, end, line, column))
     }
 
     #[test]
@@ -183,10 +334,10 @@ mod tests {
         assert_eq!(
             tokens,
             vec![
-                Token::new(TokenType::Plus, loc(1)),
-                Token::new(TokenType::Minus, loc(1)),
-                Token::new(TokenType::Slash, loc(1)),
-                Token::new(TokenType::Eof, loc(1))
+                Token::new(TokenType::Plus, loc(1, 1, 0, 1)),
+                Token::new(TokenType::Minus, loc(1, 4, 3, 4)),
+                Token::new(TokenType::Slash, loc(1, 6, 5, 6)),
+                Token::new(TokenType::Eof, loc(1, 7, 6, 6))
             ]
         );
     }
@@ -198,11 +349,11 @@ mod tests {
         assert_eq!(
             tokens,
             vec![
-                Token::new(TokenType::Plus, loc(1)),
-                Token::new(TokenType::EqualEqual, loc(1)),
-                Token::new(TokenType::Bang, loc(2)),
-                Token::new(TokenType::Bang, loc(2)),
-                Token::new(TokenType::Eof, loc(2))
+                Token::new(TokenType::Plus, loc(1, 1, 0, 1)),
+                Token::new(TokenType::EqualEqual, loc(1, 3, 2, 4)),
+                Token::new(TokenType::Bang, loc(2, 1, 11, 12)),
+                Token::new(TokenType::Bang, loc(2, 2, 12, 13)),
+                Token::new(TokenType::Eof, loc(2, 3, 13, 13))
             ]
         );
     }
@@ -214,9 +365,9 @@ mod tests {
         assert_eq!(
             tokens,
             vec![
-                Token::new(TokenType::String("Hello world!".to_string()), loc(1)),
-                Token::new(TokenType::SemiColon, loc(1)),
-                Token::new(TokenType::Eof, loc(1))
+                Token::new(TokenType::String("Hello world!".to_string()), loc(1, 1, 0, 14)),
+                Token::new(TokenType::SemiColon, loc(1, 16, 15, 16)),
+                Token::new(TokenType::Eof, loc(1, 17, 16, 16))
             ]
         );
     }
@@ -228,10 +379,10 @@ mod tests {
         assert_eq!(
             tokens,
             vec![
-                Token::new(TokenType::String("string 123.0".to_string()), loc(1)),
-                Token::new(TokenType::Number(123.0), loc(1)),
-                Token::new(TokenType::SemiColon, loc(1)),
-                Token::new(TokenType::Eof, loc(1))
+                Token::new(TokenType::String("string 123.0".to_string()), loc(1, 1, 0, 14)),
+                Token::new(TokenType::Number(123.0), loc(1, 16, 15, 20)),
+                Token::new(TokenType::SemiColon, loc(1, 21, 20, 21)),
+                Token::new(TokenType::Eof, loc(1, 22, 21, 21))
             ]
         );
     }
@@ -243,11 +394,11 @@ mod tests {
         assert_eq!(
             tokens,
             vec![
-                Token::new(TokenType::Number(12.3), loc(1)),
-                Token::new(TokenType::Plus, loc(1)),
-                Token::new(TokenType::Number(0.0), loc(1)),
-                Token::new(TokenType::SemiColon, loc(1)),
-                Token::new(TokenType::Eof, loc(1))
+                Token::new(TokenType::Number(12.3), loc(1, 1, 0, 4)),
+                Token::new(TokenType::Plus, loc(1, 5, 4, 5)),
+                Token::new(TokenType::Number(0.0), loc(1, 6, 5, 8)),
+                Token::new(TokenType::SemiColon, loc(1, 9, 8, 9)),
+                Token::new(TokenType::Eof, loc(1, 10, 9, 9))
             ]
         );
     }
@@ -259,12 +410,12 @@ mod tests {
         assert_eq!(
             tokens,
             vec![
-                Token::new(TokenType::Var, loc(1)),
-                Token::new(TokenType::Identifier("string".to_string()), loc(1)),
-                Token::new(TokenType::Equal, loc(1)),
-                Token::new(TokenType::String("Hello world!".to_string()), loc(1)),
-                Token::new(TokenType::SemiColon, loc(1)),
-                Token::new(TokenType::Eof, loc(1))
+                Token::new(TokenType::Var, loc(1, 1, 0, 3)),
+                Token::new(TokenType::Identifier("string".to_string()), loc(1, 5, 4, 10)),
+                Token::new(TokenType::Equal, loc(1, 12, 11, 12)),
+                Token::new(TokenType::String("Hello world!".to_string()), loc(1, 14, 13, 27)),
+                Token::new(TokenType::SemiColon, loc(1, 28, 27, 28)),
+                Token::new(TokenType::Eof, loc(1, 29, 28, 28))
             ]
         );
     }
@@ -276,16 +427,16 @@ mod tests {
         assert_eq!(
             tokens,
             vec![
-                Token::new(TokenType::LeftParen, loc(1)),
-                Token::new(TokenType::Number(1f32), loc(1)),
-                Token::new(TokenType::Plus, loc(1)),
-                Token::new(TokenType::Number(2f32), loc(1)),
-                Token::new(TokenType::RightParen, loc(1)),
-                Token::new(TokenType::Star, loc(1)),
-                Token::new(TokenType::Number(3f32), loc(1)),
-                Token::new(TokenType::EqualEqual, loc(2)),
-                Token::new(TokenType::Number(9f32), loc(2)),
-                Token::new(TokenType::Eof, loc(2))
+                Token::new(TokenType::LeftParen, loc(1, 1, 0, 1)),
+                Token::new(TokenType::Number(1f64), loc(1, 2, 1, 2)),
+                Token::new(TokenType::Plus, loc(1, 3, 2, 3)),
+                Token::new(TokenType::Number(2f64), loc(1, 4, 3, 4)),
+                Token::new(TokenType::RightParen, loc(1, 5, 4, 5)),
+                Token::new(TokenType::Star, loc(1, 7, 6, 7)),
+                Token::new(TokenType::Number(3f64), loc(1, 9, 8, 9)),
+                Token::new(TokenType::EqualEqual, loc(2, 2, 12, 14)),
+                Token::new(TokenType::Number(9f64), loc(2, 5, 15, 16)),
+                Token::new(TokenType::Eof, loc(2, 6, 16, 16))
             ]
         );
     }
@@ -297,13 +448,13 @@ mod tests {
         assert_eq!(
             tokens,
             vec![
-                Token::new(TokenType::Fun, loc(1)),
-                Token::new(TokenType::Identifier("foo".to_string()), loc(1)),
-                Token::new(TokenType::LeftParen, loc(1)),
-                Token::new(TokenType::RightParen, loc(1)),
-                Token::new(TokenType::LeftBrace, loc(1)),
-                Token::new(TokenType::RightBrace, loc(1)),
-                Token::new(TokenType::Eof, loc(1))
+                Token::new(TokenType::Fun, loc(1, 1, 0, 3)),
+                Token::new(TokenType::Identifier("foo".to_string()), loc(1, 5, 4, 7)),
+                Token::new(TokenType::LeftParen, loc(1, 8, 7, 8)),
+                Token::new(TokenType::RightParen, loc(1, 9, 8, 9)),
+                Token::new(TokenType::LeftBrace, loc(1, 10, 9, 10)),
+                Token::new(TokenType::RightBrace, loc(1, 11, 10, 11)),
+                Token::new(TokenType::Eof, loc(1, 12, 11, 11))
             ]
         );
     }
@@ -322,23 +473,189 @@ mod tests {
         assert_eq!(
             tokens,
             vec![
-                Token::new(TokenType::Fun, loc(1)),
-                Token::new(TokenType::Identifier("underscores_are_valid".to_string()), loc(1)),
-                Token::new(TokenType::LeftParen, loc(1)),
-                Token::new(TokenType::RightParen, loc(1)),
-                Token::new(TokenType::LeftBrace, loc(1)),
-                Token::new(TokenType::Var, loc(3)),
-                Token::new(TokenType::Identifier("foo".to_string()), loc(3)),
-                Token::new(TokenType::Equal, loc(3)),
-                Token::new(TokenType::String("baz".to_string()), loc(3)),
-                Token::new(TokenType::SemiColon, loc(3)),
-                Token::new(TokenType::LeftParen, loc(4)),
-                Token::new(TokenType::Number(1.0), loc(4)),
-                Token::new(TokenType::Plus, loc(4)),
-                Token::new(TokenType::Number(2.0), loc(4)),
-                Token::new(TokenType::RightParen, loc(4)),
-                Token::new(TokenType::RightBrace, loc(4)),
-                Token::new(TokenType::Eof, loc(5))
+                Token::new(TokenType::Fun, loc(1, 1, 0, 3)),
+                Token::new(
+                    TokenType::Identifier("underscores_are_valid".to_string()),
+                    loc(1, 5, 4, 25)
+                ),
+                Token::new(TokenType::LeftParen, loc(1, 26, 25, 26)),
+                Token::new(TokenType::RightParen, loc(1, 27, 26, 27)),
+                Token::new(TokenType::LeftBrace, loc(1, 29, 28, 29)),
+                Token::new(TokenType::Var, loc(3, 5, 59, 62)),
+                Token::new(TokenType::Identifier("foo".to_string()), loc(3, 9, 63, 66)),
+                Token::new(TokenType::Equal, loc(3, 13, 67, 68)),
+                Token::new(TokenType::String("baz".to_string()), loc(3, 15, 69, 74)),
+                Token::new(TokenType::SemiColon, loc(3, 20, 74, 75)),
+                Token::new(TokenType::LeftParen, loc(4, 5, 102, 103)),
+                Token::new(TokenType::Number(1.0), loc(4, 6, 103, 104)),
+                Token::new(TokenType::Plus, loc(4, 7, 104, 105)),
+                Token::new(TokenType::Number(2.0), loc(4, 8, 105, 106)),
+                Token::new(TokenType::RightParen, loc(4, 9, 106, 107)),
+                Token::new(TokenType::RightBrace, loc(4, 11, 108, 109)),
+                Token::new(TokenType::Eof, loc(5, 9, 118, 118))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scan_ternary_operator() {
+        let mut source = SourceCode::new("ready? a : b", "unittest.lox".to_string());
+        let tokens = source.scan_tokens();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::new(TokenType::Identifier("ready".to_string()), loc(1, 1, 0, 5)),
+                Token::new(TokenType::Question, loc(1, 6, 5, 6)),
+                Token::new(TokenType::Identifier("a".to_string()), loc(1, 8, 7, 8)),
+                Token::new(TokenType::Colon, loc(1, 10, 9, 10)),
+                Token::new(TokenType::Identifier("b".to_string()), loc(1, 12, 11, 12)),
+                Token::new(TokenType::Eof, loc(1, 13, 12, 12))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_columns_count_characters_not_bytes() {
+        let mut source = SourceCode::new("\"é\" +\n  -", "unittest.lox".to_string());
+        let tokens = source.scan_tokens();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::new(TokenType::String("é".to_string()), loc(1, 1, 0, 4)),
+                Token::new(TokenType::Plus, loc(1, 5, 5, 6)),
+                Token::new(TokenType::Minus, loc(2, 3, 9, 10)),
+                Token::new(TokenType::Eof, loc(2, 4, 10, 10))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexical_errors_become_error_tokens() {
+        let mut source = SourceCode::new("1.2.3 @ 4\n\"open", "unittest.lox".to_string());
+        let tokens = source.scan_tokens();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::new(
+                    TokenType::Error("Invalid number '1.2.3'.".to_string()),
+                    loc(1, 1, 0, 5)
+                ),
+                Token::new(
+                    TokenType::Error("Unexpected character '@'.".to_string()),
+                    loc(1, 7, 6, 7)
+                ),
+                Token::new(TokenType::Number(4.0), loc(1, 9, 8, 9)),
+                Token::new(
+                    TokenType::Error("Unterminated string.".to_string()),
+                    loc(2, 1, 10, 15)
+                ),
+                Token::new(TokenType::Eof, loc(2, 6, 15, 15))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multi_line_string_keeps_lines_and_columns() {
+        let mut source = SourceCode::new("\"one\ntwo\" +\n -", "unittest.lox".to_string());
+        let tokens = source.scan_tokens();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::new(TokenType::String("one\ntwo".to_string()), loc(1, 1, 0, 9)),
+                Token::new(TokenType::Plus, loc(2, 6, 10, 11)),
+                Token::new(TokenType::Minus, loc(3, 2, 13, 14)),
+                Token::new(TokenType::Eof, loc(3, 3, 14, 14))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let mut source = SourceCode::new(r#""a\n\t\"\\\u{e9}\u{1F600}""#, "unittest.lox".to_string());
+        let tokens = source.scan_tokens();
+        assert_eq!(
+            tokens[0].token_type,
+            TokenType::String("a\n\t\"\\é😀".to_string())
+        );
+        assert_eq!(tokens.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_escapes_are_errors() {
+        let mut source = SourceCode::new(r#""\q \u{zz} \u{110000}" ;"#, "unittest.lox".to_string());
+        let tokens = source.scan_tokens();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::new(
+                    TokenType::Error("Invalid escape sequence '\\q'.".to_string()),
+                    loc(1, 2, 1, 3)
+                ),
+                Token::new(
+                    TokenType::Error("Invalid unicode escape '\\u{'.".to_string()),
+                    loc(1, 5, 4, 7)
+                ),
+                Token::new(
+                    TokenType::Error("Invalid unicode escape '\\u{110000}'.".to_string()),
+                    loc(1, 12, 11, 21)
+                ),
+                Token::new(TokenType::String(" zz} ".to_string()), loc(1, 1, 0, 22)),
+                Token::new(TokenType::SemiColon, loc(1, 24, 23, 24)),
+                Token::new(TokenType::Eof, loc(1, 25, 24, 24))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_block_comments() {
+        let mut source = SourceCode::new("+ /* a /* b\n */ c */ - // end", "unittest.lox".to_string());
+        let tokens = source.scan_tokens();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::new(TokenType::Plus, loc(1, 1, 0, 1)),
+                Token::new(TokenType::Minus, loc(2, 10, 21, 22)),
+                Token::new(TokenType::Eof, loc(2, 18, 29, 29))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_block_comment() {
+        let mut source = SourceCode::new("- /* /* */\n", "unittest.lox".to_string());
+        let tokens = source.scan_tokens();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::new(TokenType::Minus, loc(1, 1, 0, 1)),
+                Token::new(
+                    TokenType::Error("Unterminated block comment.".to_string()),
+                    loc(1, 3, 2, 11)
+                ),
+                Token::new(TokenType::Eof, loc(2, 1, 11, 11))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_line_comment_at_end_of_file_keeps_line() {
+        let mut source = SourceCode::new("+ // done", "unittest.lox".to_string());
+        let tokens = source.scan_tokens();
+        assert_eq!(tokens[1], Token::new(TokenType::Eof, loc(1, 10, 9, 9)));
+    }
+
+    #[test]
+    fn test_crlf_line_endings_are_whitespace() {
+        let mut source = SourceCode::new("print 1;\r\n-", "unittest.lox".to_string());
+        let tokens = source.scan_tokens();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::new(TokenType::Print, loc(1, 1, 0, 5)),
+                Token::new(TokenType::Number(1.0), loc(1, 7, 6, 7)),
+                Token::new(TokenType::SemiColon, loc(1, 8, 7, 8)),
+                Token::new(TokenType::Minus, loc(2, 1, 10, 11)),
+                Token::new(TokenType::Eof, loc(2, 2, 11, 11))
             ]
         );
     }
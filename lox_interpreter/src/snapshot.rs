@@ -0,0 +1,218 @@
+use std::fmt::Write;
+use std::fs;
+use std::time::Duration;
+
+use crate::core::errors::LoxError;
+use crate::gc::GcStats;
+
+/// Everything on the tree-walking interpreter's heap at one moment, for working out offline
+/// what is keeping memory alive. Objects are numbered by their position in `objects`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeapSnapshot {
+    pub stats: GcStats,
+    pub roots: Vec<SnapshotRoot>,
+    pub objects: Vec<SnapshotObject>,
+}
+
+/// An object held from outside the heap, such as the global scope.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotRoot {
+    pub name: String,
+    pub object: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotObject {
+    pub id: usize,
+    /// One of "scope", "function", "class" or "instance".
+    pub kind: &'static str,
+    /// The object as `print` shows it, or the variables a scope defines.
+    pub name: String,
+    /// An estimate of the bytes the object takes up, not counting the objects it refers to.
+    pub size: usize,
+    pub references: Vec<SnapshotReference>,
+    /// The names of the root and the references followed from it to get here, by the shortest
+    /// path. `None` when the object is garbage that hasn't been collected yet.
+    pub retaining_path: Option<Vec<String>>,
+}
+
+/// A reference to another object, named after the variable, field or method holding it, or
+/// "enclosing", "closure", "superclass" or "class".
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotReference {
+    pub name: String,
+    pub object: usize,
+}
+
+impl HeapSnapshot {
+    /// The snapshot as JSON. Sizes are in bytes and pause times in microseconds.
+    pub fn to_json(&self) -> String {
+        let stats = &self.stats;
+        let mut json = String::from("{\n  \"stats\": {");
+        let _ = writeln!(
+            json,
+            "\"objects\": {}, \"bytes_in_use\": {}, \"bytes_live\": {}, \"allocations\": {}, \
+             \"bytes_allocated\": {}, \"collections\": {}, \"objects_freed\": {}, \
+             \"total_pause_us\": {}, \"max_pause_us\": {}, \"last_pause_us\": {}}},",
+            stats.objects,
+            stats.bytes_in_use,
+            stats.bytes_live,
+            stats.allocations,
+            stats.bytes_allocated,
+            stats.collections,
+            stats.objects_freed,
+            micros(stats.total_pause),
+            micros(stats.max_pause),
+            micros(stats.last_pause),
+        );
+
+        let roots: Vec<String> = self
+            .roots
+            .iter()
+            .map(|root| format!("{{\"name\": {}, \"object\": {}}}", quote(&root.name), root.object))
+            .collect();
+        let _ = writeln!(json, "  \"roots\": [{}],", roots.join(", "));
+
+        let objects: Vec<String> = self.objects.iter().map(object_json).collect();
+        match objects.is_empty() {
+            true => json.push_str("  \"objects\": []\n}\n"),
+            false => {
+                let _ = write!(
+                    json,
+                    "  \"objects\": [\n    {}\n  ]\n}}\n",
+                    objects.join(",\n    ")
+                );
+            }
+        }
+        json
+    }
+
+    pub fn write_file(&self, path: &str) -> Result<(), LoxError> {
+        fs::write(path, self.to_json()).map_err(|err| LoxError::new_syscall(path, 0, err.to_string()))
+    }
+}
+
+fn object_json(object: &SnapshotObject) -> String {
+    let references: Vec<String> = object
+        .references
+        .iter()
+        .map(|reference| {
+            format!(
+                "{{\"name\": {}, \"object\": {}}}",
+                quote(&reference.name),
+                reference.object
+            )
+        })
+        .collect();
+    let retaining_path = match &object.retaining_path {
+        Some(path) => {
+            let names: Vec<String> = path.iter().map(|name| quote(name)).collect();
+            format!("[{}]", names.join(", "))
+        }
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\"id\": {}, \"kind\": {}, \"name\": {}, \"size\": {}, \"references\": [{}], \"retaining_path\": {}}}",
+        object.id,
+        quote(object.kind),
+        quote(&object.name),
+        object.size,
+        references.join(", "),
+        retaining_path
+    )
+}
+
+fn micros(duration: Duration) -> u128 {
+    duration.as_micros()
+}
+
+/// The string as a JSON string literal.
+fn quote(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("plain", "\"plain\"")]
+    #[case("say \"hi\"", "\"say \\\"hi\\\"\"")]
+    #[case("a\\b", "\"a\\\\b\"")]
+    #[case("line\nbreak\t", "\"line\\nbreak\\t\"")]
+    #[case("\u{1}", "\"\\u0001\"")]
+    fn test_quote(#[case] string: &str, #[case] expected: &str) {
+        assert_eq!(quote(string), expected);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let snapshot = HeapSnapshot {
+            stats: GcStats {
+                objects: 2,
+                collections: 1,
+                max_pause: Duration::from_micros(15),
+                ..GcStats::default()
+            },
+            roots: vec![SnapshotRoot {
+                name: "globals".to_string(),
+                object: 0,
+            }],
+            objects: vec![
+                SnapshotObject {
+                    id: 0,
+                    kind: "scope",
+                    name: "a".to_string(),
+                    size: 64,
+                    references: vec![SnapshotReference {
+                        name: "a".to_string(),
+                        object: 1,
+                    }],
+                    retaining_path: Some(vec!["globals".to_string()]),
+                },
+                SnapshotObject {
+                    id: 1,
+                    kind: "instance",
+                    name: "A instance".to_string(),
+                    size: 32,
+                    references: vec![],
+                    retaining_path: None,
+                },
+            ],
+        };
+        assert_eq!(
+            snapshot.to_json(),
+            concat!(
+                "{\n",
+                "  \"stats\": {\"objects\": 2, \"bytes_in_use\": 0, \"bytes_live\": 0, \"allocations\": 0, ",
+                "\"bytes_allocated\": 0, \"collections\": 1, \"objects_freed\": 0, ",
+                "\"total_pause_us\": 0, \"max_pause_us\": 15, \"last_pause_us\": 0},\n",
+                "  \"roots\": [{\"name\": \"globals\", \"object\": 0}],\n",
+                "  \"objects\": [\n",
+                "    {\"id\": 0, \"kind\": \"scope\", \"name\": \"a\", \"size\": 64, ",
+                "\"references\": [{\"name\": \"a\", \"object\": 1}], \"retaining_path\": [\"globals\"]},\n",
+                "    {\"id\": 1, \"kind\": \"instance\", \"name\": \"A instance\", \"size\": 32, ",
+                "\"references\": [], \"retaining_path\": null}\n",
+                "  ]\n",
+                "}\n"
+            )
+        );
+    }
+}
@@ -16,6 +16,8 @@ pub enum TokenType {
     SemiColon,
     Slash,
     Star,
+    Question,
+    Colon,
 
     // One or two character tokens.
     Bang,
@@ -30,12 +32,13 @@ pub enum TokenType {
     // Literals.
     Identifier(String),
     String(String),
-    Number(f32),
+    Number(f64),
 
     // Keywords.
     And,
     Break,
     Class,
+    Continue,
     Else,
     False,
     Fun,
@@ -51,6 +54,10 @@ pub enum TokenType {
     Var,
     While,
 
+    // Source the scanner couldn't make sense of, with a message saying why. The parser reports
+    // these as syntax errors.
+    Error(String),
+
     Eof,
 }
 
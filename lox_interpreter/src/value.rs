@@ -1,11 +1,226 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
+use std::rc::Rc;
+
+use crate::environment::ScopeRef;
+use crate::heap::Heap;
+use crate::parser::FunctionDecl;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     Nil,
     Boolean(bool),
-    Number(f32),
+    Number(f64),
     String(String),
+    Function(Rc<LoxFunction>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<LoxInstance>),
+    NativeFunction(Rc<NativeFunction>),
+}
+
+pub struct LoxFunction {
+    pub declaration: Rc<FunctionDecl>,
+    pub closure: ScopeRef,
+    pub is_initializer: bool,
+}
+
+impl LoxFunction {
+    pub fn new(declaration: Rc<FunctionDecl>, closure: ScopeRef) -> Self {
+        LoxFunction {
+            declaration,
+            closure,
+            is_initializer: false,
+        }
+    }
+
+    pub fn new_method(declaration: Rc<FunctionDecl>, closure: ScopeRef) -> Self {
+        let is_initializer = declaration.name.token_type.to_string() == "init";
+        LoxFunction {
+            declaration,
+            closure,
+            is_initializer,
+        }
+    }
+
+    /// Returns a copy of this method whose closure has `this` bound to the instance.
+    pub fn bind(&self, instance: Rc<LoxInstance>, heap: &mut Heap) -> Rc<LoxFunction> {
+        let closure = heap.alloc_scope(Some(&self.closure));
+        closure
+            .borrow_mut()
+            .define("this".to_string(), Value::Instance(instance));
+        heap.alloc_function(LoxFunction {
+            declaration: Rc::clone(&self.declaration),
+            closure,
+            is_initializer: self.is_initializer,
+        })
+    }
+
+    pub fn arity(&self) -> usize {
+        self.declaration.params.len()
+    }
+
+    pub fn name(&self) -> String {
+        self.declaration.name.token_type.to_string()
+    }
+}
+
+impl PartialEq for LoxFunction {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.declaration, &other.declaration) && Rc::ptr_eq(&self.closure, &other.closure)
+    }
+}
+
+// The closure can hold this function, so a derived Debug would recurse forever.
+impl fmt::Debug for LoxFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LoxFunction({})", self.name())
+    }
+}
+
+/// The Rust side of a native function. It gets the evaluated arguments, of which there are always
+/// as many as the function's arity, and returns either the result or a runtime error message.
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, String>;
+
+/// A function implemented in Rust that the host makes available to scripts, like `clock()`.
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: usize,
+    function: Box<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn new<F>(name: &str, arity: usize, function: F) -> Self
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        NativeFunction {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        }
+    }
+
+    pub fn call(&self, arguments: &[Value]) -> Result<Value, String> {
+        (self.function)(arguments)
+    }
+}
+
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeFunction({})", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct LoxClass {
+    pub name: String,
+    pub superclass: Option<Rc<LoxClass>>,
+    pub methods: HashMap<String, Rc<LoxFunction>>,
+}
+
+impl LoxClass {
+    pub fn new(
+        name: String,
+        superclass: Option<Rc<LoxClass>>,
+        methods: HashMap<String, Rc<LoxFunction>>,
+    ) -> Self {
+        LoxClass {
+            name,
+            superclass,
+            methods,
+        }
+    }
+
+    /// Looks up a method on this class and then up the superclass chain.
+    pub fn find_method(&self, name: &str) -> Option<Rc<LoxFunction>> {
+        match self.methods.get(name) {
+            Some(method) => Some(Rc::clone(method)),
+            None => match &self.superclass {
+                Some(superclass) => superclass.find_method(name),
+                None => None,
+            },
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        match self.find_method("init") {
+            Some(initializer) => initializer.arity(),
+            None => 0,
+        }
+    }
+}
+
+impl PartialEq for LoxClass {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+pub struct LoxInstance {
+    pub class: Rc<LoxClass>,
+    fields: RefCell<HashMap<String, Value>>,
+}
+
+impl LoxInstance {
+    pub fn new(class: Rc<LoxClass>) -> Self {
+        LoxInstance {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Looks up a field first and then a method, which is bound to this instance.
+    pub fn get(self: &Rc<Self>, name: &str, heap: &mut Heap) -> Option<Value> {
+        if let Some(value) = self.fields.borrow().get(name) {
+            return Some(value.clone());
+        }
+        self.class
+            .find_method(name)
+            .map(|method| Value::Function(method.bind(Rc::clone(self), heap)))
+    }
+
+    pub fn set(&self, name: &str, value: Value) {
+        self.fields.borrow_mut().insert(name.to_string(), value);
+    }
+
+    /// Calls `visit` with each field's name and value. Returns false without calling it when the
+    /// fields are being written to.
+    pub fn visit_fields(&self, mut visit: impl FnMut(&str, &Value)) -> bool {
+        match self.fields.try_borrow() {
+            Ok(fields) => {
+                fields.iter().for_each(|(name, value)| visit(name, value));
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Removes every field, for the garbage collector to break cycles through this instance.
+    pub fn clear_fields(&self) {
+        if let Ok(mut fields) = self.fields.try_borrow_mut() {
+            fields.clear();
+        }
+    }
+}
+
+impl PartialEq for LoxInstance {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// Fields can refer back to the instance, so a derived Debug would recurse forever.
+impl fmt::Debug for LoxInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LoxInstance({})", self.class.name)
+    }
 }
 
 impl Value {
@@ -26,11 +241,39 @@ impl fmt::Display for Value {
                 false => write!(f, "false"),
             },
             Value::String(x) => write!(f, "{}", x),
-            Value::Number(x) => write!(f, "{}", x),
+            Value::Number(x) => f.write_str(&format_number(*x)),
+            Value::Function(function) => write!(f, "<fn {}>", function.name()),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Value::NativeFunction(_) => write!(f, "<native fn>"),
         }
     }
 }
 
+/// Formats a number the way the reference Lox implementation does: integers without a trailing
+/// `.0`, and very large or very small magnitudes in scientific notation such as `1.0E10`.
+pub fn format_number(x: f64) -> String {
+    if x.is_nan() {
+        return "NaN".to_string();
+    }
+    if x.is_infinite() {
+        return match x > 0.0 {
+            true => "Infinity".to_string(),
+            false => "-Infinity".to_string(),
+        };
+    }
+    let magnitude = x.abs();
+    if magnitude != 0.0 && !(1e-3..1e7).contains(&magnitude) {
+        let scientific = format!("{:E}", x);
+        let (mantissa, exponent) = scientific.split_once('E').unwrap_or((&scientific, "0"));
+        return match mantissa.contains('.') {
+            true => format!("{}E{}", mantissa, exponent),
+            false => format!("{}.0E{}", mantissa, exponent),
+        };
+    }
+    format!("{}", x)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -44,4 +287,20 @@ mod tests {
     fn test_is_truthy(#[case] value: Value, #[case] expected: bool) {
         assert_eq!(value.is_truthy(), expected);
     }
+
+    #[rstest]
+    #[case(1.0, "1")]
+    #[case(-3.0, "-3")]
+    #[case(2.5, "2.5")]
+    #[case(-0.0, "-0")]
+    #[case(0.1 + 0.2, "0.30000000000000004")]
+    #[case(16777217.0, "1.6777217E7")]
+    #[case(1e10, "1.0E10")]
+    #[case(1234567.0, "1234567")]
+    #[case(0.0001, "1.0E-4")]
+    #[case(f64::NAN, "NaN")]
+    #[case(f64::NEG_INFINITY, "-Infinity")]
+    fn test_number_display(#[case] number: f64, #[case] expected: &str) {
+        assert_eq!(Value::Number(number).to_string(), expected);
+    }
 }
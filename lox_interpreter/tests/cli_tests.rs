@@ -0,0 +1,31 @@
+use std::env;
+use std::fs;
+use std::process::Command;
+
+/// Runs `rlox run` on the source and returns what it wrote to stderr.
+fn run_errors(name: &str, source: &str) -> String {
+    let path = env::temp_dir().join(format!("rlox_cli_{}_{}.lox", name, std::process::id()));
+    fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg("run")
+        .arg(&path)
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn test_syntax_errors_are_labelled() {
+    let errors = run_errors("syntax", "print 1 + ;\n");
+    assert!(errors.contains("1 | print 1 + ;\n"), "{}", errors);
+    assert!(errors.contains("^ expected expression\n"), "{}", errors);
+}
+
+#[test]
+fn test_runtime_errors_are_labelled() {
+    let errors = run_errors("runtime", "print nope;\n");
+    assert!(errors.contains("1 | print nope;\n"), "{}", errors);
+    assert!(errors.contains("^^^^ undefined variable\n"), "{}", errors);
+}
@@ -0,0 +1,31 @@
+use std::path::Path;
+
+use lox_interpreter::conformance::{run_dir, Backend};
+use lox_interpreter::gc::GcConfig;
+
+fn assert_data_scripts_pass(backend: Backend, gc: GcConfig) {
+    let results = run_dir(Path::new("./lox_interpreter/data"), backend, gc).unwrap();
+    assert!(!results.is_empty());
+    let failures: Vec<String> = results
+        .iter()
+        .filter(|result| !result.passed())
+        .map(|result| result.to_string())
+        .collect();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+#[test]
+fn test_data_scripts_do_what_their_comments_expect() {
+    assert_data_scripts_pass(Backend::TreeWalker, GcConfig::default());
+}
+
+#[test]
+fn test_data_scripts_behave_the_same_on_the_vm() {
+    assert_data_scripts_pass(Backend::Vm, GcConfig::default());
+}
+
+#[test]
+fn test_data_scripts_pass_when_collecting_on_every_allocation() {
+    assert_data_scripts_pass(Backend::TreeWalker, GcConfig::stress());
+    assert_data_scripts_pass(Backend::Vm, GcConfig::stress());
+}
@@ -1,10 +1,11 @@
-use std::{io::{BufRead, self}, fs::File};
-
 use rstest::*;
-use lox_interpreter::{parser::{Expr, Literal, Stmt, ParseResult}, tokens::{TokenType, Token}, core::{location::Location}, interpreter::{InterpreterState, Interpreter}, runhelpers::{raw_source_to_ast, filepath_to_ast}};
+use lox_interpreter::{parser::{Expr, Literal, Stmt, ParseResult}, tokens::{TokenType, Token}, core::{errors::LoxError, location::{Location, Span}}, interpreter::{ErrorPolicy, InterpreterState, Interpreter}, runhelpers::raw_source_to_ast, value::Value};
 
-fn loc(line: usize) -> Location {
-    Location::Line("integration-test.lox".to_string(), line)
+fn loc(line: usize, column: usize, start: usize, end: usize) -> Location {
+    Location::new_span(
+        "integration-test.lox".to_string(),
+        Span::new(start, end, line, column),
+    )
 }
 
 #[test]
@@ -17,14 +18,14 @@ fn test_expression_to_ast() {
         Expr::Binary(
             Box::new(
             Expr::Grouping(Box::new(Expr::Binary(
-                Box::new(Expr::Literal(loc(1), Literal::Number(1.0))), 
-                Token::new(TokenType::Plus, loc(1)), 
-                Box::new(Expr::Literal(loc(1), Literal::Number(2.0)))
+                Box::new(Expr::Literal(loc(1, 2, 1, 2), Literal::Number(1.0))), 
+                Token::new(TokenType::Plus, loc(1, 4, 3, 4)), 
+                Box::new(Expr::Literal(loc(1, 6, 5, 6), Literal::Number(2.0)))
             )))),
-            Token::new(TokenType::Slash, loc(1)),
-            Box::new(Expr::Literal(loc(1), Literal::Number(3.0))))),
-        Token::new(TokenType::EqualEqual, loc(1)),
-        Box::new(Expr::Literal(loc(1), Literal::Number(1.0)))))];
+            Token::new(TokenType::Slash, loc(1, 9, 8, 9)),
+            Box::new(Expr::Literal(loc(1, 11, 10, 11), Literal::Number(3.0))))),
+        Token::new(TokenType::EqualEqual, loc(1, 13, 12, 14)),
+        Box::new(Expr::Literal(loc(1, 16, 15, 16), Literal::Number(1.0)))))];
     assert_eq!(ast_result, Ok(ast))
 }
 
@@ -33,42 +34,156 @@ fn test_variable_declaration() {
     let s = "var foo;";
     let ast_result = raw_source_to_ast(s, "integration-test.lox");
     let ast = vec![
-        Stmt::Var(Token::new(TokenType::Identifier("foo".to_string()), loc(1)), Expr::Literal(loc(1), Literal::Nil))
+        Stmt::Var(Token::new(TokenType::Identifier("foo".to_string()), loc(1, 5, 4, 7)), Expr::Literal(loc(1, 5, 4, 7), Literal::Nil))
     ];
     assert_eq!(ast_result, Ok(ast));
 }
 
-fn expected_output(file_path: &str) -> String {
-    let file = File::open(file_path).unwrap();
-    let contents = io::BufReader::new(file).lines();
-    let mut result = Vec::new();
-    // Consumes the iterator, returns an (Optional) String
-    for line in contents {
-        let l = line.unwrap();
-        if l.starts_with("//") {
-            let parts: Vec<&str> = l.split("//").collect();
-            let expected = parts[1][1..].to_string();
-            result.push(expected + "\n")
-        } else {
-            break
-        }
-    }
-    return result.join("");
+#[test]
+fn test_inheriting_from_non_class_is_runtime_error() {
+    let s = "var NotAClass = \"nope\";\nclass Sub < NotAClass {}";
+    let ast = raw_source_to_ast(s, "integration-test.lox").must();
+    let state = &mut InterpreterState::<Vec<u8>>::default();
+    let errors = Interpreter::new(ast).interpret(state);
+    assert_eq!(
+        errors,
+        vec![LoxError::RuntimeError(
+            loc(2, 13, 36, 45),
+            "Superclass must be a class.".to_string()
+        )]
+    );
+}
+
+#[test]
+fn test_class_inheriting_from_itself_is_syntax_error() {
+    let ast_result = raw_source_to_ast("class Oops < Oops {}", "integration-test.lox");
+    assert_eq!(
+        ast_result,
+        Err(vec![LoxError::SyntaxError(
+            loc(1, 14, 13, 17),
+            "A class can't inherit from itself.".to_string()
+        )])
+    );
 }
 
 #[rstest]
-#[case("conditional-and-false.lox")]
-#[case("conditional-and-true.lox")]
-#[case("conditional-false.lox")]
-#[case("conditional-true.lox")]
-#[case("while-loop.lox")]
-#[case("for-loop.lox")]
-fn test_program_output_is_expected(#[case] filename: &str) {
-    let filepath = format!("./data/{}", filename);
-    let expected = expected_output(&filepath);
-    let ast = filepath_to_ast(&filepath).must();
+#[case("break;", loc(1, 1, 0, 5), "Can't use 'break' outside of a loop.")]
+#[case("continue;", loc(1, 1, 0, 8), "Can't use 'continue' outside of a loop.")]
+#[case(
+    "while (true) { fun f() { break; } }",
+    loc(1, 26, 25, 30),
+    "Can't use 'break' outside of a loop."
+)]
+fn test_loop_control_outside_loop_is_syntax_error(
+    #[case] source: &str,
+    #[case] location: Location,
+    #[case] message: &str,
+) {
+    let ast_result = raw_source_to_ast(source, "integration-test.lox");
+    assert_eq!(
+        ast_result,
+        Err(vec![LoxError::SyntaxError(location, message.to_string())])
+    );
+}
+
+#[test]
+fn test_expression_location_covers_whole_expression() {
+    let ast = raw_source_to_ast("var x =\n  a.b(1) + -c;", "integration-test.lox").must();
+    let Stmt::Var(_, initializer) = &ast[0] else {
+        panic!("Expected a var statement.")
+    };
+    assert_eq!(initializer.location(), loc(2, 3, 10, 21));
+    assert_eq!(ast[0].location(), loc(1, 5, 4, 21));
+}
+
+#[test]
+fn test_clock_is_a_native_function() {
+    let s = "var start = clock();\nprint clock() >= start;\nprint clock;";
+    let ast = raw_source_to_ast(s, "integration-test.lox").must();
     let state = &mut InterpreterState::<Vec<u8>>::default();
-    let interpreter = Interpreter::new(ast);
-    interpreter.interpret(state);
-    assert_eq!(state.get_writer(), expected)
-}
\ No newline at end of file
+    let errors = Interpreter::new(ast).interpret(state);
+    assert_eq!(errors, vec![]);
+    assert_eq!(state.get_writer(), "true\n<native fn>\n");
+}
+
+#[test]
+fn test_host_can_define_native_functions() {
+    let state = &mut InterpreterState::<Vec<u8>>::default();
+    state.set_error_policy(ErrorPolicy::Continue);
+    state.define_native("double", 1, |arguments| match &arguments[0] {
+        Value::Number(x) => Ok(Value::Number(x * 2.0)),
+        _ => Err("double() takes a number.".to_string()),
+    });
+
+    let s = "print double(21);\ndouble(1, 2);\ndouble(\"a\");";
+    let ast = raw_source_to_ast(s, "integration-test.lox").must();
+    let errors = Interpreter::new(ast).interpret(state);
+    assert_eq!(state.get_writer(), "42\n");
+    assert_eq!(
+        errors,
+        vec![
+            LoxError::RuntimeError(loc(2, 12, 29, 30), "Expected 1 arguments but got 2.".to_string()),
+            LoxError::RuntimeError(loc(3, 11, 42, 43), "double() takes a number.".to_string()),
+        ]
+    );
+}
+
+#[rstest]
+#[case(ErrorPolicy::Abort, "1\n", 1)]
+#[case(ErrorPolicy::Continue, "1\n3\n", 2)]
+fn test_error_policy(#[case] policy: ErrorPolicy, #[case] output: &str, #[case] error_count: usize) {
+    let s = "print 1;\nprint nope;\nprint 3;\nprint nope;";
+    let ast = raw_source_to_ast(s, "integration-test.lox").must();
+    let state = &mut InterpreterState::<Vec<u8>>::default();
+    state.set_error_policy(policy);
+    let errors = Interpreter::new(ast).interpret(state);
+    assert_eq!(state.get_writer(), output);
+    assert_eq!(errors.len(), error_count);
+}
+
+#[test]
+fn test_runtime_error_has_stack_trace() {
+    let s = "\
+fun inner() { return nope; }
+fun outer() { return inner(); }
+outer();";
+    let ast = raw_source_to_ast(s, "integration-test.lox").must();
+    let state = &mut InterpreterState::<Vec<u8>>::default();
+    let errors = Interpreter::new(ast).interpret(state);
+    assert_eq!(
+        errors,
+        vec![LoxError::RuntimeError(
+            loc(1, 22, 21, 25),
+            "Undefined variable: nope
+in inner(), called at integration-test.lox:2:28
+in outer(), called at integration-test.lox:3:7"
+                .to_string()
+        )]
+    );
+}
+
+#[test]
+fn test_deep_recursion_is_a_stack_overflow_error() {
+    // Every call recurses on the Rust stack, and a debug build needs far more of it per call
+    // than the test harness's threads have.
+    let run = || {
+        let s = "fun d(n) { if (n == 0) return 0; return 1 + d(n - 1); }
+print d(2000);
+print d(10);";
+        let ast = raw_source_to_ast(s, "integration-test.lox").must();
+        let state = &mut InterpreterState::<Vec<u8>>::default();
+        state.set_error_policy(ErrorPolicy::Continue);
+        let errors = Interpreter::new(ast).interpret(state);
+        (state.get_writer().to_string(), errors)
+    };
+    let (output, errors) = std::thread::Builder::new()
+        .stack_size(256 * 1024 * 1024)
+        .spawn(run)
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(output, "10\n");
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(&errors[0], LoxError::RuntimeError(location, message)
+        if *location == loc(1, 52, 51, 52) && message.starts_with("Stack overflow.\nin d(), called at")));
+}